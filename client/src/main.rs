@@ -1,4 +1,4 @@
-use std::{env, sync::Arc};
+use std::{env, net::IpAddr, sync::Arc};
 
 use argh::FromArgs;
 
@@ -16,7 +16,7 @@ use webrtc::{
         udp_mux::{UDPMuxDefault, UDPMuxParams},
         udp_network::UDPNetwork,
     },
-    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    ice_transport::ice_server::RTCIceServer,
     interceptor::registry::Registry,
     peer_connection::configuration::RTCConfiguration,
     peer_connection::sdp::session_description::RTCSessionDescription,
@@ -27,6 +27,8 @@ use webrtc::{
 
 use gst::prelude::{ElementExt, GstObjectExt};
 
+use omniroom_network::{IpNetwork, NatCandidateType, NatMapping, NetworkConfig, PortRange};
+
 /// Whip signaling broadcast server
 #[derive(FromArgs)]
 struct Args {
@@ -38,9 +40,57 @@ struct Args {
     #[argh(option, short = 'm')]
     udp_mux_port: Option<u16>,
 
-    /// an optional list of ips separated by '|' to setup nat 1 to 1
+    /// an optional list of public ips separated by ',' to setup nat 1 to 1,
+    /// each for all local ips or written public/local
     #[argh(option, short = 'i')]
     nat_ips: Option<String>,
+
+    /// how the nat ips are advertised: host (default) or srflx
+    #[argh(option)]
+    nat_candidate_type: Option<NatCandidateType>,
+
+    /// an interface candidates are gathered on, can be repeated (default: all)
+    #[argh(option)]
+    interface: Vec<String>,
+
+    /// an interface candidates are not gathered on, can be repeated
+    #[argh(option)]
+    deny_interface: Vec<String>,
+
+    /// an ip or network candidates are gathered on, can be repeated
+    /// (default: all)
+    #[argh(option)]
+    allow_ip: Vec<IpNetwork>,
+
+    /// an ip or network candidates are not gathered on, can be repeated
+    #[argh(option)]
+    deny_ip: Vec<IpNetwork>,
+
+    /// do not gather IPv4 candidates
+    #[argh(switch)]
+    no_ipv4: bool,
+
+    /// do not gather IPv6 candidates
+    #[argh(switch)]
+    no_ipv6: bool,
+
+    /// an optional range of ports like 10000-20000 for the udp sockets,
+    /// unused with udp muxing
+    #[argh(option)]
+    udp_port_range: Option<PortRange>,
+
+    /// a STUN or TURN server url, can be repeated (default:
+    /// stun:stun.l.google.com:19302)
+    #[argh(option)]
+    ice_server: Vec<String>,
+
+    /// an optional username for the TURN servers
+    #[argh(option)]
+    turn_username: Option<String>,
+
+    /// an optional credential for the TURN servers
+    #[argh(option)]
+    turn_credential: Option<String>,
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -54,16 +104,24 @@ pub enum Error {
 
     #[error("Gstreamer Error: {0}")]
     GstreamerError(#[from] gst::glib::Error),
+
+    #[error("Network Error: {0}")]
+    NetworkError(String),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args: Args = argh::from_env();
 
+    let mut ice_servers = args.ice_server;
+    if ice_servers.is_empty() {
+        ice_servers.push("stun:stun.l.google.com:19302".to_owned());
+    }
     let default_config = RTCConfiguration {
         ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
+            urls: ice_servers,
+            username: args.turn_username.unwrap_or_default(),
+            credential: args.turn_credential.unwrap_or_default(),
         }],
         ..Default::default()
     };
@@ -75,6 +133,24 @@ async fn main() -> Result<()> {
     // Settings
     let mut setting_engine = SettingEngine::default();
 
+    let nat_ips = args
+        .nat_ips
+        .or(env::var("NAT_IPS").ok())
+        .unwrap_or_default();
+    let network = NetworkConfig {
+        nat_ips: NatMapping::parse_list(&nat_ips)
+            .map_err(|err| Error::NetworkError(format!("NAT ip {err}")))?,
+        nat_candidate_type: args.nat_candidate_type.unwrap_or(NatCandidateType::Host),
+        interfaces: args.interface,
+        deny_interfaces: args.deny_interface,
+        allow_ips: args.allow_ip,
+        deny_ips: args.deny_ip,
+        ipv4: !args.no_ipv4,
+        ipv6: !args.no_ipv6,
+        udp_port_range: args.udp_port_range,
+    };
+    network.validate().map_err(Error::NetworkError)?;
+
     let mut udp_mux_port: Option<u16> = match env::var("UDP_MUX_PORT").ok() {
         Some(port) => port.parse::<u16>().ok(),
         None => None,
@@ -82,6 +158,12 @@ async fn main() -> Result<()> {
     if let Some(udp_port) = args.udp_mux_port {
         udp_mux_port = Some(udp_port);
     }
+    // The mux only takes IPv4
+    let udp_mux_addresses = match udp_mux_port {
+        Some(_) => vec![IpAddr::from([0, 0, 0, 0])],
+        None => Vec::new(),
+    };
+    network.apply(&mut setting_engine, &udp_mux_addresses);
     if let Some(udp_port) = udp_mux_port {
         println!("Using UDP MUX port: {}", udp_port);
         let udp_socket = UdpSocket::bind(("0.0.0.0", udp_port)).await.unwrap();
@@ -89,19 +171,6 @@ async fn main() -> Result<()> {
         setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
     }
 
-    let mut nat_ips: Option<String> = env::var("NAT_IPS").ok();
-    if let Some(ips) = args.nat_ips {
-        nat_ips = Some(ips);
-    }
-    if let Some(nat_ips) = nat_ips {
-        let nat_ips: Vec<String> = nat_ips.split(',').map(|ip| ip.to_string()).collect();
-        println!("Using NAT 1 to 1 with IPs:");
-        for ip in nat_ips.clone().into_iter() {
-            println!(" - {}", ip);
-        }
-        setting_engine.set_nat_1to1_ips(nat_ips, RTCIceCandidateType::Host);
-    }
-
     let mut registry = Registry::new();
     registry = register_default_interceptors(registry, &mut m).unwrap();
     let api = APIBuilder::new()
@@ -193,6 +262,7 @@ async fn main() -> Result<()> {
 
     let answer = client
         .post(args.server_url)
+        .header("Content-Type", "application/sdp")
         .body(late_offer)
         .send()
         .await?
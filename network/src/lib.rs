@@ -0,0 +1,465 @@
+//! Where the ICE candidates of the server and the client are gathered, and
+//! how they are advertised
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use webrtc::{
+    api::setting_engine::SettingEngine,
+    ice::{
+        network_type::NetworkType,
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::ice_candidate_type::RTCIceCandidateType,
+};
+
+/// Where ICE candidates are gathered and how they are advertised
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// Public IPs of a 1 to 1 NAT, for all local IPs or mapped to one each
+    pub nat_ips: Vec<NatMapping>,
+    /// Whether the public IPs replace the host candidates or come as server
+    /// reflexive candidates along them
+    pub nat_candidate_type: NatCandidateType,
+    /// Interfaces candidates are gathered on, all of them without any
+    pub interfaces: Vec<String>,
+    pub deny_interfaces: Vec<String>,
+    /// IPs or networks candidates are gathered on, all of them without any
+    pub allow_ips: Vec<IpNetwork>,
+    pub deny_ips: Vec<IpNetwork>,
+    pub ipv4: bool,
+    pub ipv6: bool,
+    /// Ports of the per connection UDP sockets, unused with the UDP mux
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_port_range: Option<PortRange>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            nat_ips: Vec::new(),
+            nat_candidate_type: NatCandidateType::Host,
+            interfaces: Vec::new(),
+            deny_interfaces: Vec::new(),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            ipv4: true,
+            ipv6: true,
+            udp_port_range: None,
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.ipv4 && !self.ipv6 {
+            return Err("IPv4 and IPv6 cannot both be disabled".to_string());
+        }
+        // Each family takes either one public IP for all, or one per local IP
+        for ipv4 in [true, false] {
+            let mappings: Vec<&NatMapping> = self
+                .nat_ips
+                .iter()
+                .filter(|mapping| mapping.public.is_ipv4() == ipv4)
+                .collect();
+            let sole = mappings.iter().filter(|mapping| mapping.local.is_none());
+            if sole.count() > 0 && mappings.len() > 1 {
+                return Err(format!(
+                    "IPv{} NAT ips take a single public IP, or public/local mappings",
+                    if ipv4 { 4 } else { 6 }
+                ));
+            }
+        }
+        if let Some(range) = &self.udp_port_range
+            && (range.min == 0 || range.min > range.max)
+        {
+            return Err(format!("UDP port range {range} is empty or starts at 0"));
+        }
+        Ok(())
+    }
+
+    /// Feeds the settings to the engine, a UDP mux set afterwards replacing
+    /// the port range. Only the families and addresses the mux is bound to
+    /// are gathered, `udp_mux` being empty without one
+    pub fn apply(&self, setting_engine: &mut SettingEngine, udp_mux: &[IpAddr]) {
+        if !self.nat_ips.is_empty() {
+            println!("Using NAT 1 to 1 with IPs:");
+            for mapping in &self.nat_ips {
+                println!(" - {mapping}");
+            }
+            setting_engine.set_nat_1to1_ips(
+                self.nat_ips.iter().map(ToString::to_string).collect(),
+                self.nat_candidate_type.into(),
+            );
+        }
+
+        let mut network_types = Vec::new();
+        if self.ipv4 && (udp_mux.is_empty() || udp_mux.iter().any(IpAddr::is_ipv4)) {
+            network_types.push(NetworkType::Udp4);
+        }
+        if self.ipv6 && (udp_mux.is_empty() || udp_mux.iter().any(IpAddr::is_ipv6)) {
+            network_types.push(NetworkType::Udp6);
+        }
+        setting_engine.set_network_types(network_types);
+
+        if !self.interfaces.is_empty() || !self.deny_interfaces.is_empty() {
+            let (allowed, denied) = (self.interfaces.clone(), self.deny_interfaces.clone());
+            setting_engine.set_interface_filter(Box::new(move |interface| {
+                (allowed.is_empty() || allowed.iter().any(|allowed| allowed == interface))
+                    && !denied.iter().any(|denied| denied == interface)
+            }));
+        }
+        // A family bound to given addresses only has those
+        let bound: Vec<IpAddr> = udp_mux
+            .iter()
+            .filter(|ip| {
+                !udp_mux
+                    .iter()
+                    .any(|other| other.is_unspecified() && other.is_ipv4() == ip.is_ipv4())
+            })
+            .copied()
+            .collect();
+        if !self.allow_ips.is_empty() || !self.deny_ips.is_empty() || !bound.is_empty() {
+            let (allowed, denied) = (self.allow_ips.clone(), self.deny_ips.clone());
+            setting_engine.set_ip_filter(Box::new(move |ip| {
+                (allowed.is_empty() || allowed.iter().any(|network| network.contains(ip)))
+                    && !denied.iter().any(|network| network.contains(ip))
+                    && (bound.contains(&ip)
+                        || !bound.iter().any(|bound| bound.is_ipv4() == ip.is_ipv4()))
+            }));
+        }
+
+        if let Some(range) = &self.udp_port_range {
+            // The range was validated
+            if let Ok(ephemeral) = EphemeralUDP::new(range.min, range.max) {
+                setting_engine.set_udp_network(UDPNetwork::Ephemeral(ephemeral));
+            }
+        }
+    }
+}
+
+/// A public IP of a 1 to 1 NAT, written `public` or `public/local`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct NatMapping {
+    pub public: IpAddr,
+    pub local: Option<IpAddr>,
+}
+
+impl NatMapping {
+    /// Mappings separated by ','
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|mapping| !mapping.is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+impl FromStr for NatMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |ip: &str| {
+            ip.trim()
+                .parse::<IpAddr>()
+                .map_err(|err| format!("'{ip}': {err}"))
+        };
+        let (public, local) = match s.split_once('/') {
+            Some((public, local)) => (parse(public)?, Some(parse(local)?)),
+            None => (parse(s)?, None),
+        };
+        if local.is_some_and(|local| local.is_ipv4() != public.is_ipv4()) {
+            return Err(format!("'{s}' maps IPs of different families"));
+        }
+        Ok(Self { public, local })
+    }
+}
+
+impl TryFrom<String> for NatMapping {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<NatMapping> for String {
+    fn from(mapping: NatMapping) -> Self {
+        mapping.to_string()
+    }
+}
+
+impl fmt::Display for NatMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.local {
+            Some(local) => write!(f, "{}/{local}", self.public),
+            None => write!(f, "{}", self.public),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NatCandidateType {
+    Host,
+    Srflx,
+}
+
+impl FromStr for NatCandidateType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Self::Host),
+            "srflx" => Ok(Self::Srflx),
+            _ => Err(format!(
+                "unknown candidate type '{s}', expected host or srflx"
+            )),
+        }
+    }
+}
+
+impl From<NatCandidateType> for RTCIceCandidateType {
+    fn from(candidate_type: NatCandidateType) -> Self {
+        match candidate_type {
+            NatCandidateType::Host => RTCIceCandidateType::Host,
+            NatCandidateType::Srflx => RTCIceCandidateType::Srflx,
+        }
+    }
+}
+
+/// An IP or a network, written `ip` or `ip/prefix`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNetwork {
+    ip: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.ip, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None),
+        };
+        let ip: IpAddr = ip.parse().map_err(|err| format!("'{s}': {err}"))?;
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("'{s}': invalid prefix length"))?,
+        };
+        Ok(Self { ip, prefix })
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpNetwork> for String {
+    fn from(network: IpNetwork) -> Self {
+        network.to_string()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix)
+    }
+}
+
+/// Inclusive range of ports, written `min-max`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{s}' is not a port range like 10000-20000");
+        let (min, max) = s.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            min: min.trim().parse().map_err(|_| invalid())?,
+            max: max.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_network_contains() {
+        let lan = network("192.168.1.0/24");
+        assert!(lan.contains(ip("192.168.1.0")));
+        assert!(lan.contains(ip("192.168.1.255")));
+        assert!(!lan.contains(ip("192.168.0.255")));
+        assert!(!lan.contains(ip("192.168.2.0")));
+        assert!(!lan.contains(ip("::ffff:192.168.1.1")));
+    }
+
+    #[test]
+    fn ipv6_network_contains() {
+        let ula = network("fd00::/8");
+        assert!(ula.contains(ip("fd12:3456::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+        assert!(!ula.contains(ip("10.0.0.1")));
+        let subnet = network("2001:db8:0:1::/64");
+        assert!(subnet.contains(ip("2001:db8:0:1:ffff:ffff:ffff:ffff")));
+        assert!(!subnet.contains(ip("2001:db8:0:2::")));
+    }
+
+    #[test]
+    fn network_prefix_edges() {
+        assert!(network("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(network("::/0").contains(ip("ffff::1")));
+        assert!(network("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!network("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(network("::1/128").contains(ip("::1")));
+        assert!(!network("::1/128").contains(ip("::2")));
+        // A lone IP is a network of its own
+        assert_eq!(network("10.0.0.1"), network("10.0.0.1/32"));
+        assert_eq!(network("::1"), network("::1/128"));
+    }
+
+    #[test]
+    fn network_parse_errors() {
+        for invalid in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "fd00::/x"] {
+            assert!(invalid.parse::<IpNetwork>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn nat_mappings() {
+        let sole: NatMapping = "203.0.113.1".parse().unwrap();
+        assert_eq!((sole.public, sole.local), (ip("203.0.113.1"), None));
+        let mapped: NatMapping = "203.0.113.1/10.0.0.1".parse().unwrap();
+        assert_eq!(
+            (mapped.public, mapped.local),
+            (ip("203.0.113.1"), Some(ip("10.0.0.1")))
+        );
+        assert_eq!(mapped.to_string(), "203.0.113.1/10.0.0.1");
+
+        assert!("203.0.113.1/fd00::1".parse::<NatMapping>().is_err());
+        assert!("203.0.113".parse::<NatMapping>().is_err());
+        assert!("203.0.113.1/".parse::<NatMapping>().is_err());
+    }
+
+    #[test]
+    fn nat_mapping_lists() {
+        let mappings =
+            NatMapping::parse_list(" 203.0.113.1/10.0.0.1, ,2001:db8::1/fd00::1,").unwrap();
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[1].public, ip("2001:db8::1"));
+        assert!(NatMapping::parse_list("").unwrap().is_empty());
+        assert!(NatMapping::parse_list("203.0.113.1,nope").is_err());
+    }
+
+    #[test]
+    fn port_ranges() {
+        let range: PortRange = "10000-20000".parse().unwrap();
+        assert_eq!((range.min, range.max), (10000, 20000));
+        assert_eq!(" 1 - 2 ".parse::<PortRange>().unwrap().to_string(), "1-2");
+        for invalid in ["10000", "10000-", "-20000", "10000-70000", "a-b"] {
+            assert!(invalid.parse::<PortRange>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn validation() {
+        let config = |nat_ips: &str, udp_port_range: Option<&str>| NetworkConfig {
+            nat_ips: NatMapping::parse_list(nat_ips).unwrap(),
+            udp_port_range: udp_port_range.map(|range| range.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(
+            config("203.0.113.1,2001:db8::1", Some("1-1"))
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            config("203.0.113.1/10.0.0.1,203.0.113.2/10.0.0.2", None)
+                .validate()
+                .is_ok()
+        );
+        assert!(
+            config("203.0.113.1,203.0.113.2/10.0.0.2", None)
+                .validate()
+                .is_err()
+        );
+        assert!(config("", Some("20000-10000")).validate().is_err());
+        assert!(config("", Some("0-10")).validate().is_err());
+        let no_family = NetworkConfig {
+            ipv4: false,
+            ipv6: false,
+            ..Default::default()
+        };
+        assert!(no_family.validate().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Fan-out of synthetic RTP to hundreds of viewers, with and without a slow one
+//!
+//! Run with `cargo bench --bench fanout`
+
+#[path = "../src/fanout.rs"]
+// Its tests are left out of the bench, not their helpers
+#[cfg_attr(test, allow(dead_code, unused_imports))]
+mod fanout;
+
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use webrtc::{
+    interceptor::Attributes,
+    rtp::{header::Header, packet::Packet},
+    track::track_local::TrackLocalWriter,
+};
+
+use fanout::Fanout;
+
+const PACKETS: usize = 20_480;
+const BURST: usize = 64;
+
+/// A viewer track counting what it is given, optionally taking its time
+#[derive(Debug, Default)]
+struct Viewer {
+    written: AtomicUsize,
+    delay: Option<Duration>,
+}
+
+#[async_trait]
+impl TrackLocalWriter for Viewer {
+    async fn write_rtp_with_attributes(
+        &self,
+        packet: &Packet,
+        _attr: &Attributes,
+    ) -> webrtc::error::Result<usize> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+        self.written.fetch_add(1, Ordering::Relaxed);
+        Ok(packet.payload.len())
+    }
+
+    async fn write(&self, b: &[u8]) -> webrtc::error::Result<usize> {
+        Ok(b.len())
+    }
+}
+
+fn synthetic_packet(sequence_number: u16) -> Packet {
+    Packet {
+        header: Header {
+            version: 2,
+            payload_type: 96,
+            sequence_number,
+            timestamp: u32::from(sequence_number) * 3000,
+            ssrc: 0x1234_5678,
+            ..Default::default()
+        },
+        payload: vec![0u8; 1200].into(),
+    }
+}
+
+async fn run(viewers: usize, slow_viewers: usize) {
+    let fanout = Fanout::default();
+    let fast: Vec<Arc<Viewer>> = (0..viewers).map(|_| Arc::new(Viewer::default())).collect();
+    let slow: Vec<Arc<Viewer>> = (0..slow_viewers)
+        .map(|_| {
+            Arc::new(Viewer {
+                delay: Some(Duration::from_millis(5)),
+                ..Default::default()
+            })
+        })
+        .collect();
+    let tasks: Vec<_> = fast
+        .iter()
+        .chain(slow.iter())
+        .map(|viewer| fanout.subscribe(viewer.clone()))
+        .collect();
+
+    let delivered = || {
+        fast.iter()
+            .map(|viewer| viewer.written.load(Ordering::Relaxed))
+            .sum::<usize>()
+    };
+
+    let start = Instant::now();
+    let mut publishing = Duration::ZERO;
+    for burst in 0..PACKETS / BURST {
+        let publish_start = Instant::now();
+        for i in 0..BURST {
+            fanout.publish(synthetic_packet((burst * BURST + i) as u16));
+        }
+        publishing += publish_start.elapsed();
+
+        // Pace the publisher on the fast viewers only, the slow one must not hold anybody back
+        let expected = (burst + 1) * BURST * viewers;
+        while delivered() < expected {
+            tokio::task::yield_now().await;
+        }
+    }
+    let elapsed = start.elapsed();
+    let slow_delivered: usize = slow
+        .iter()
+        .map(|viewer| viewer.written.load(Ordering::Relaxed))
+        .sum();
+
+    println!(
+        "{viewers:>4} viewers + {slow_viewers} slow: publish {:>7.2}µs/packet, \
+         {:>9.0} packets/s delivered, slow viewers got {slow_delivered}/{PACKETS} packets{}",
+        publishing.as_secs_f64() * 1e6 / PACKETS as f64,
+        delivered() as f64 / elapsed.as_secs_f64(),
+        if fanout.take_lagged() {
+            " and lagged"
+        } else {
+            ""
+        },
+    );
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    for viewers in [100, 300, 500] {
+        run(viewers, 0).await;
+        run(viewers, 1).await;
+    }
+}
@@ -0,0 +1,675 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a bearer token is being presented for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Publish,
+    Play,
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "publish" => Ok(Action::Publish),
+            "play" => Ok(Action::Play),
+            _ => Err(format!("unknown action '{s}', expected publish or play")),
+        }
+    }
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Publish => "publish",
+            Action::Play => "play",
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuthError {
+    /// No token was sent
+    #[error("Unauthorized: {0}")]
+    MissingToken(String),
+
+    /// The token is unknown, malformed or expired
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// The token is valid but does not grant the requested action
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+
+    /// The authorization backend could not be reached
+    #[error("Authorization unavailable: {0}")]
+    Unavailable(String),
+}
+
+impl AuthError {
+    /// Value of the `WWW-Authenticate` header sent along a refusal (RFC 6750)
+    pub fn www_authenticate(&self) -> Option<String> {
+        match self {
+            // Without credentials there is no error to tell (RFC 6750 section 3.1)
+            AuthError::MissingToken(_) => Some("Bearer realm=\"omniroom\"".to_string()),
+            AuthError::Unauthorized(reason) => Some(format!(
+                "Bearer realm=\"omniroom\", error=\"invalid_token\", error_description=\"{reason}\""
+            )),
+            AuthError::Forbidden(reason) => Some(format!(
+                "Bearer realm=\"omniroom\", error=\"insufficient_scope\", error_description=\"{reason}\""
+            )),
+            AuthError::Unavailable(_) => None,
+        }
+    }
+}
+
+/// Decides which stream a bearer token gives access to
+#[async_trait]
+pub trait StreamAuthorizer: Send + Sync {
+    /// Returns the stream key `token` grants `action` on
+    async fn authorize(&self, action: Action, token: &str) -> Result<String, AuthError>;
+}
+
+/// Legacy behaviour: the token is the stream key and anyone may publish with it
+///
+/// It grants no play token, as any stream name would do, so streams needing
+/// a viewer token only take the ones their policy lists.
+pub struct AllowAll;
+
+#[async_trait]
+impl StreamAuthorizer for AllowAll {
+    async fn authorize(&self, action: Action, token: &str) -> Result<String, AuthError> {
+        match action {
+            Action::Publish => Ok(token.to_string()),
+            Action::Play => Err(AuthError::Unauthorized(
+                "no authorizer grants play tokens".to_string(),
+            )),
+        }
+    }
+}
+
+/// Stream names mapped to their secret, loaded from a TOML or JSON file
+///
+/// ```toml
+/// default = "s3cret"
+/// studio = "an0ther"
+/// ```
+pub struct StaticKeys {
+    keys: HashMap<String, String>,
+}
+
+impl StaticKeys {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            keys: config::load_file(path)?,
+        })
+    }
+}
+
+#[async_trait]
+impl StreamAuthorizer for StaticKeys {
+    async fn authorize(&self, action: Action, token: &str) -> Result<String, AuthError> {
+        if action != Action::Publish {
+            return Err(AuthError::Forbidden(
+                "stream keys only grant publish".to_string(),
+            ));
+        }
+        self.keys
+            .iter()
+            .find(|(_, secret)| constant_time_eq(secret.as_bytes(), token.as_bytes()))
+            .map(|(stream, _)| stream.clone())
+            .ok_or_else(|| AuthError::Unauthorized("unknown stream key".to_string()))
+    }
+}
+
+/// Self-contained expiring tokens signed with a shared secret
+///
+/// Tokens look like `<stream>:<action>:<expires>:<signature>` where `expires`
+/// is a unix timestamp and `signature` is the hex encoded HMAC-SHA256 of
+/// `<stream>:<action>:<expires>`.
+pub struct HmacTokens {
+    secret: Vec<u8>,
+}
+
+impl HmacTokens {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self {
+            secret: secret.into(),
+        }
+    }
+
+    fn mac(&self, message: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size");
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    pub fn sign(&self, stream: &str, action: Action, expires: u64) -> String {
+        let message = format!("{stream}:{}:{expires}", action.as_str());
+        let signature = hex::encode(self.mac(&message).finalize().into_bytes());
+        format!("{message}:{signature}")
+    }
+}
+
+#[async_trait]
+impl StreamAuthorizer for HmacTokens {
+    async fn authorize(&self, action: Action, token: &str) -> Result<String, AuthError> {
+        let malformed = || AuthError::Unauthorized("malformed token".to_string());
+
+        let (message, signature) = token.rsplit_once(':').ok_or_else(malformed)?;
+        let signature = hex::decode(signature).map_err(|_| malformed())?;
+        self.mac(message)
+            .verify_slice(&signature)
+            .map_err(|_| AuthError::Unauthorized("bad signature".to_string()))?;
+
+        let mut fields = message.rsplitn(3, ':');
+        let expires = fields.next().ok_or_else(malformed)?;
+        let token_action = fields.next().ok_or_else(malformed)?;
+        let stream = fields.next().ok_or_else(malformed)?;
+
+        let expires: u64 = expires.parse().map_err(|_| malformed())?;
+        if expires < unix_now() {
+            return Err(AuthError::Unauthorized("token expired".to_string()));
+        }
+        if token_action != action.as_str() {
+            return Err(AuthError::Forbidden(format!(
+                "token does not grant {}",
+                action.as_str()
+            )));
+        }
+        Ok(stream.to_string())
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    action: Action,
+    token: &'a str,
+}
+
+#[derive(Deserialize)]
+struct WebhookResponse {
+    stream: String,
+}
+
+/// Asks an external HTTP service whether a token is accepted
+///
+/// The service receives `{"action": "publish" | "play", "token": "..."}` and
+/// answers 2xx with `{"stream": "<stream key>"}` to accept, 401 or 403 to refuse.
+pub struct Webhook {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("Failed to build the webhook HTTP client");
+        Self { url, client }
+    }
+}
+
+#[async_trait]
+impl StreamAuthorizer for Webhook {
+    async fn authorize(&self, action: Action, token: &str) -> Result<String, AuthError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&WebhookRequest { action, token })
+            .send()
+            .await
+            .map_err(|err| AuthError::Unavailable(err.to_string()))?;
+
+        match response.status() {
+            status if status.is_success() => response
+                .json::<WebhookResponse>()
+                .await
+                .map(|body| body.stream)
+                .map_err(|err| AuthError::Unavailable(err.to_string())),
+            reqwest::StatusCode::UNAUTHORIZED => {
+                Err(AuthError::Unauthorized("refused by webhook".to_string()))
+            }
+            reqwest::StatusCode::FORBIDDEN => {
+                Err(AuthError::Forbidden("refused by webhook".to_string()))
+            }
+            status => Err(AuthError::Unavailable(format!("webhook answered {status}"))),
+        }
+    }
+}
+
+/// Who may watch a stream
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "policy", rename_all = "lowercase")]
+pub enum ViewerPolicy {
+    /// Anyone knowing the stream name
+    #[default]
+    Public,
+
+    /// Viewers must present one of `tokens`, or a token the stream authorizer
+    /// accepts for playing this stream
+    Token {
+        #[serde(default)]
+        tokens: Vec<String>,
+    },
+
+    /// Viewers must present a play token signed with `secret`, see [`HmacTokens`]
+    Signed { secret: String },
+}
+
+/// A stream's viewer policy, and whether the stream directory lists it
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct StreamPolicy {
+    #[serde(flatten)]
+    viewers: ViewerPolicy,
+    /// Only public streams are listed without it
+    listed: Option<bool>,
+}
+
+/// Per stream viewer policies, loaded from a TOML or JSON file
+///
+/// ```toml
+/// [default]
+/// policy = "public"
+///
+/// [streams.backstage]
+/// policy = "token"
+/// tokens = ["crew-only"]
+///
+/// [streams.premiere]
+/// policy = "signed"
+/// secret = "watch-secret"
+/// listed = true
+/// ```
+#[derive(Default, Deserialize)]
+pub struct ViewerPolicies {
+    #[serde(default)]
+    default: StreamPolicy,
+    #[serde(default)]
+    streams: HashMap<String, StreamPolicy>,
+}
+
+impl ViewerPolicies {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        config::load_file(path)
+    }
+
+    fn stream_policy(&self, stream: &str) -> &StreamPolicy {
+        self.streams.get(stream).unwrap_or(&self.default)
+    }
+
+    pub fn policy(&self, stream: &str) -> &ViewerPolicy {
+        &self.stream_policy(stream).viewers
+    }
+
+    /// Whether the stream directory shows `stream`
+    pub fn is_listed(&self, stream: &str) -> bool {
+        let policy = self.stream_policy(stream);
+        policy
+            .listed
+            .unwrap_or(matches!(policy.viewers, ViewerPolicy::Public))
+    }
+
+    /// Checks that `token` lets a viewer watch `stream`
+    pub async fn authorize(
+        &self,
+        authorizer: &dyn StreamAuthorizer,
+        stream: &str,
+        token: Option<&str>,
+    ) -> Result<(), AuthError> {
+        let missing = || AuthError::MissingToken("this stream needs a viewer token".to_string());
+        let granted = match self.policy(stream) {
+            ViewerPolicy::Public => return Ok(()),
+            ViewerPolicy::Token { tokens } => {
+                let token = token.ok_or_else(missing)?;
+                if tokens
+                    .iter()
+                    .any(|allowed| constant_time_eq(allowed.as_bytes(), token.as_bytes()))
+                {
+                    return Ok(());
+                }
+                authorizer.authorize(Action::Play, token).await?
+            }
+            ViewerPolicy::Signed { secret } => {
+                let token = token.ok_or_else(missing)?;
+                HmacTokens::new(secret.as_str())
+                    .authorize(Action::Play, token)
+                    .await?
+            }
+        };
+
+        if granted != stream {
+            return Err(AuthError::Forbidden(
+                "token is for another stream".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether `a` and `b` are equal, in a time not telling where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    /// A file of the test's own in the temporary directory
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("omniroom-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn hmac_tokens_round_trip() {
+        let tokens = HmacTokens::new("s3cret");
+        let expires = unix_now() + 60;
+        let token = tokens.sign("studio", Action::Publish, expires);
+        assert_eq!(
+            token,
+            format!("studio:publish:{expires}:{}", &token[token.len() - 64..])
+        );
+        assert_eq!(
+            tokens.authorize(Action::Publish, &token).await.unwrap(),
+            "studio"
+        );
+    }
+
+    #[tokio::test]
+    async fn hmac_tokens_keep_colons_in_stream_names() {
+        let tokens = HmacTokens::new("s3cret");
+        let token = tokens.sign("live:studio:1", Action::Play, unix_now() + 60);
+        assert_eq!(
+            tokens.authorize(Action::Play, &token).await.unwrap(),
+            "live:studio:1"
+        );
+    }
+
+    #[tokio::test]
+    async fn hmac_tokens_refuse_expired_forged_and_malformed_tokens() {
+        let tokens = HmacTokens::new("s3cret");
+        let expired = tokens.sign("studio", Action::Publish, unix_now() - 1);
+        assert!(matches!(
+            tokens.authorize(Action::Publish, &expired).await,
+            Err(AuthError::Unauthorized(_))
+        ));
+
+        let token = tokens.sign("studio", Action::Publish, unix_now() + 60);
+        let forged = token.replacen("studio", "other", 1);
+        assert!(matches!(
+            tokens.authorize(Action::Publish, &forged).await,
+            Err(AuthError::Unauthorized(_))
+        ));
+        let other_secret = HmacTokens::new("guess");
+        assert!(matches!(
+            other_secret.authorize(Action::Publish, &token).await,
+            Err(AuthError::Unauthorized(_))
+        ));
+
+        for malformed in ["", "studio", "studio:publish:soon:00", "studio:zz"] {
+            assert!(matches!(
+                tokens.authorize(Action::Publish, malformed).await,
+                Err(AuthError::Unauthorized(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn hmac_tokens_grant_only_their_action() {
+        let tokens = HmacTokens::new("s3cret");
+        let expires = unix_now() + 60;
+        let publish = tokens.sign("studio", Action::Publish, expires);
+        let play = tokens.sign("studio", Action::Play, expires);
+        assert!(matches!(
+            tokens.authorize(Action::Play, &publish).await,
+            Err(AuthError::Forbidden(_))
+        ));
+        assert!(matches!(
+            tokens.authorize(Action::Publish, &play).await,
+            Err(AuthError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn static_keys_from_toml_and_json() {
+        let toml = temp_file("keys.toml", "default = \"s3cret\"\nstudio = \"an0ther\"\n");
+        let json = temp_file("keys.json", r#"{"default": "s3cret", "studio": "an0ther"}"#);
+        for path in [toml, json] {
+            let keys = StaticKeys::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(
+                keys.authorize(Action::Publish, "an0ther").await.unwrap(),
+                "studio"
+            );
+            assert_eq!(
+                keys.authorize(Action::Publish, "s3cret").await.unwrap(),
+                "default"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn static_keys_refuse_unknown_keys_and_play() {
+        let path = temp_file("refusing-keys.toml", "studio = \"an0ther\"\n");
+        let keys = StaticKeys::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Neither stream names nor prefixes of a secret
+        for unknown in ["studio", "an0the", "an0ther!", ""] {
+            assert!(matches!(
+                keys.authorize(Action::Publish, unknown).await,
+                Err(AuthError::Unauthorized(_))
+            ));
+        }
+        assert!(matches!(
+            keys.authorize(Action::Play, "an0ther").await,
+            Err(AuthError::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn static_keys_need_their_file() {
+        assert!(StaticKeys::load(Path::new("/nonexistent/keys.toml")).is_err());
+        let path = temp_file("invalid-keys.toml", "studio = 3\n");
+        assert!(StaticKeys::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unreachable_webhook_is_unavailable() {
+        // Nothing listens on the discard port
+        let webhook = Webhook::new("http://127.0.0.1:9/authorize".to_string());
+        assert!(matches!(
+            webhook.authorize(Action::Publish, "token").await,
+            Err(AuthError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn missing_tokens_get_a_challenge_without_error() {
+        assert_eq!(
+            AuthError::MissingToken("missing bearer token".to_string())
+                .www_authenticate()
+                .unwrap(),
+            "Bearer realm=\"omniroom\""
+        );
+        let challenge = AuthError::Unauthorized("token expired".to_string())
+            .www_authenticate()
+            .unwrap();
+        assert!(challenge.contains("error=\"invalid_token\""));
+        assert!(
+            AuthError::Forbidden("token is for another stream".to_string())
+                .www_authenticate()
+                .unwrap()
+                .contains("error=\"insufficient_scope\"")
+        );
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_slices() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cre"));
+        assert!(!constant_time_eq(b"s3cre", b"s3cret"));
+        assert!(!constant_time_eq(b"", b"s"));
+    }
+
+    fn policies() -> ViewerPolicies {
+        toml::from_str(
+            r#"
+            [streams.backstage]
+            policy = "token"
+            tokens = ["crew-only"]
+
+            [streams.premiere]
+            policy = "signed"
+            secret = "watch-secret"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn watch_token(stream: &str, expires: u64) -> String {
+        HmacTokens::new("watch-secret").sign(stream, Action::Play, expires)
+    }
+
+    #[tokio::test]
+    async fn public_streams_need_no_token() {
+        let policies = policies();
+        assert!(policies.authorize(&AllowAll, "lobby", None).await.is_ok());
+        assert!(
+            policies
+                .authorize(&AllowAll, "lobby", Some("anything"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn token_policy_takes_listed_tokens() {
+        let policies = policies();
+        assert!(
+            policies
+                .authorize(&AllowAll, "backstage", Some("crew-only"))
+                .await
+                .is_ok()
+        );
+        assert!(matches!(
+            policies.authorize(&AllowAll, "backstage", None).await,
+            Err(AuthError::MissingToken(_))
+        ));
+        assert!(matches!(
+            policies
+                .authorize(&AllowAll, "backstage", Some("crew"))
+                .await,
+            Err(AuthError::Unauthorized(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn token_policy_fails_closed_without_an_authorizer() {
+        // The stream name would be granted back as is
+        assert!(
+            policies()
+                .authorize(&AllowAll, "backstage", Some("backstage"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn token_policy_takes_play_tokens_of_the_authorizer() {
+        let policies = policies();
+        let authorizer = HmacTokens::new("publish-secret");
+        let expires = unix_now() + 60;
+        let play = authorizer.sign("backstage", Action::Play, expires);
+        assert!(
+            policies
+                .authorize(&authorizer, "backstage", Some(&play))
+                .await
+                .is_ok()
+        );
+        let publish = authorizer.sign("backstage", Action::Publish, expires);
+        assert!(matches!(
+            policies
+                .authorize(&authorizer, "backstage", Some(&publish))
+                .await,
+            Err(AuthError::Forbidden(_))
+        ));
+        let other = authorizer.sign("premiere", Action::Play, expires);
+        assert!(matches!(
+            policies
+                .authorize(&authorizer, "backstage", Some(&other))
+                .await,
+            Err(AuthError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn signed_policy_checks_stream_and_expiry() {
+        let policies = policies();
+        let token = watch_token("premiere", unix_now() + 60);
+        assert!(
+            policies
+                .authorize(&AllowAll, "premiere", Some(&token))
+                .await
+                .is_ok()
+        );
+
+        let expired = watch_token("premiere", unix_now() - 1);
+        assert!(matches!(
+            policies
+                .authorize(&AllowAll, "premiere", Some(&expired))
+                .await,
+            Err(AuthError::Unauthorized(_))
+        ));
+        let other = watch_token("backstage", unix_now() + 60);
+        assert!(matches!(
+            policies
+                .authorize(&AllowAll, "premiere", Some(&other))
+                .await,
+            Err(AuthError::Forbidden(_))
+        ));
+        let forged = HmacTokens::new("guess").sign("premiere", Action::Play, unix_now() + 60);
+        assert!(matches!(
+            policies
+                .authorize(&AllowAll, "premiere", Some(&forged))
+                .await,
+            Err(AuthError::Unauthorized(_))
+        ));
+        assert!(matches!(
+            policies.authorize(&AllowAll, "premiere", None).await,
+            Err(AuthError::MissingToken(_))
+        ));
+    }
+}
@@ -0,0 +1,82 @@
+/// Reads big endian bit fields, and the Exp-Golomb codes of H.264
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    pub fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            let bit = (byte >> (7 - self.position % 8)) & 1;
+            value = (value << 1) | u32::from(bit);
+            self.position += 1;
+        }
+        Some(value)
+    }
+
+    /// Unsigned Exp-Golomb code, `ue(v)`
+    pub fn read_ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.read(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.read(leading_zeros)?)
+    }
+
+    /// Signed Exp-Golomb code, `se(v)`
+    pub fn read_se(&mut self) -> Option<i64> {
+        let code = i64::from(self.read_ue()?);
+        Some(if code % 2 == 1 {
+            (code + 1) / 2
+        } else {
+            -code / 2
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_across_bytes() {
+        let mut bits = BitReader::new(&[0b1010_1100, 0b0101_0000]);
+        assert_eq!(bits.read(3), Some(0b101));
+        assert_eq!(bits.read(7), Some(0b011_0001));
+        assert_eq!(bits.read(0), Some(0));
+        assert_eq!(bits.read(6), Some(0b01_0000));
+        assert_eq!(bits.read(1), None);
+    }
+
+    #[test]
+    fn reads_exp_golomb_codes() {
+        // 1, 010, 011, 00100, 00111
+        let data = [0b1010_0110, 0b0100_0011, 0b1000_0000];
+        let mut bits = BitReader::new(&data);
+        let codes: Vec<_> = std::iter::from_fn(|| bits.read_ue()).collect();
+        assert_eq!(codes, [0, 1, 2, 3, 6]);
+
+        let mut bits = BitReader::new(&data);
+        let codes: Vec<_> = std::iter::from_fn(|| bits.read_se()).collect();
+        assert_eq!(codes, [0, 1, -1, 2, -3]);
+    }
+
+    #[test]
+    fn rejects_long_exp_golomb_codes() {
+        assert_eq!(BitReader::new(&[0, 0, 0, 0, 0xff]).read_ue(), None);
+        // Truncated suffix
+        assert_eq!(BitReader::new(&[0b0000_0001]).read_ue(), None);
+        // 2^32 - 2, the largest code read
+        let largest = [0, 0, 0, 1, 0xff, 0xff, 0xff, 0xfe];
+        assert_eq!(BitReader::new(&largest).read_ue(), Some(u32::MAX - 1));
+    }
+}
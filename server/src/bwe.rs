@@ -0,0 +1,222 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+use webrtc::rtcp::transport_feedbacks::transport_layer_cc::TransportLayerCc;
+
+/// Loss below which the estimate grows, as in the GCC loss based controller
+const LOW_LOSS: f64 = 0.02;
+/// Loss above which the estimate shrinks
+const HIGH_LOSS: f64 = 0.1;
+const GROWTH: f64 = 1.08;
+/// How far above what is sent the estimate may grow on low loss, loss only
+/// telling about the bitrate actually sent
+const MAX_OVERSHOOT: f64 = 1.5;
+
+/// Bitrate a viewer can take, from the REMB, receiver reports and
+/// transport-wide congestion control feedback it sends
+#[derive(Default)]
+pub struct BandwidthEstimator {
+    remb: Option<u64>,
+    /// Worst loss fraction reported since the last update
+    loss: Option<f64>,
+    estimate: Option<u64>,
+}
+
+impl BandwidthEstimator {
+    pub fn on_remb(&mut self, bitrate: f32) {
+        self.remb = Some(bitrate as u64);
+    }
+
+    pub fn on_loss(&mut self, fraction: f64) {
+        self.loss = Some(self.loss.map_or(fraction, |loss| loss.max(fraction)));
+    }
+
+    pub fn on_twcc(&mut self, feedback: &TransportLayerCc) {
+        if feedback.packet_status_count == 0 {
+            return;
+        }
+        // Only received packets have a receive delta
+        let received = feedback.recv_deltas.len() as f64;
+        let loss = 1.0 - received / f64::from(feedback.packet_status_count);
+        self.on_loss(loss.clamp(0.0, 1.0));
+    }
+
+    /// Last estimate, if any feedback came in
+    pub fn estimate(&self) -> Option<u64> {
+        self.estimate
+    }
+
+    /// Forgets the estimate, to start over from what is sent next
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Folds in the feedback gathered since the last update, `incoming` being
+    /// the bitrate currently sent to the viewer
+    pub fn update(&mut self, incoming: u64) -> Option<u64> {
+        let current = self.estimate.unwrap_or(incoming) as f64;
+        let loss_based = match self.loss.take() {
+            // Nothing sent tells nothing about what more could be
+            Some(loss) if loss < LOW_LOSS && incoming == 0 => current,
+            Some(loss) if loss < LOW_LOSS => {
+                (current.max(incoming as f64) * GROWTH).min(incoming as f64 * MAX_OVERSHOOT)
+            }
+            Some(loss) if loss > HIGH_LOSS => incoming as f64 * (1.0 - 0.5 * loss),
+            Some(_) => current,
+            None if self.estimate.is_none() && self.remb.is_none() => return None,
+            None => current,
+        } as u64;
+
+        self.estimate = Some(match self.remb {
+            Some(remb) => loss_based.min(remb),
+            None => loss_based,
+        });
+        self.estimate
+    }
+}
+
+/// Bitrate of a stream of packets, sampled on demand
+#[derive(Debug)]
+pub struct BitrateMeter {
+    bytes: AtomicU64,
+    last_sample: std::sync::Mutex<(Instant, u64)>,
+    bitrate: AtomicU64,
+}
+
+impl Default for BitrateMeter {
+    fn default() -> Self {
+        Self {
+            bytes: AtomicU64::new(0),
+            last_sample: std::sync::Mutex::new((Instant::now(), 0)),
+            bitrate: AtomicU64::new(0),
+        }
+    }
+}
+
+impl BitrateMeter {
+    pub fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bits per second since the previous sample
+    pub fn sample(&self) -> u64 {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let mut last_sample = self.last_sample.lock().unwrap();
+        let (at, last_bytes) = *last_sample;
+        *last_sample = (Instant::now(), bytes);
+
+        let elapsed = at.elapsed().as_secs_f64();
+        let bitrate = if elapsed == 0.0 {
+            0
+        } else {
+            ((bytes - last_bytes) as f64 * 8.0 / elapsed) as u64
+        };
+        self.bitrate.store(bitrate, Ordering::Relaxed);
+        bitrate
+    }
+
+    /// Bits per second at the last sample
+    pub fn bitrate(&self) -> u64 {
+        self.bitrate.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl BitrateMeter {
+    /// A meter whose last sample was `bitrate`
+    pub fn sampled(bitrate: u64) -> Self {
+        let meter = Self::default();
+        meter.bitrate.store(bitrate, Ordering::Relaxed);
+        meter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtcp::transport_feedbacks::transport_layer_cc::RecvDelta;
+
+    use super::*;
+
+    fn with_loss(loss: f64) -> BandwidthEstimator {
+        let mut bwe = BandwidthEstimator::default();
+        bwe.on_loss(loss);
+        bwe
+    }
+
+    #[test]
+    fn no_feedback_no_estimate() {
+        assert_eq!(BandwidthEstimator::default().update(1_000_000), None);
+    }
+
+    #[test]
+    fn low_loss_grows_up_to_a_margin_over_what_is_sent() {
+        let mut bwe = with_loss(0.0);
+        assert_eq!(bwe.update(1_000_000), Some(1_080_000));
+        for _ in 0..20 {
+            bwe.on_loss(0.01);
+            bwe.update(1_000_000);
+        }
+        assert_eq!(bwe.estimate(), Some(1_500_000));
+
+        // Paused video sends nothing to grow from
+        bwe.on_loss(0.0);
+        assert_eq!(bwe.update(0), Some(1_500_000));
+    }
+
+    #[test]
+    fn mid_loss_holds_the_estimate() {
+        let mut bwe = with_loss(0.0);
+        bwe.update(1_000_000);
+        bwe.on_loss(0.05);
+        assert_eq!(bwe.update(2_000_000), Some(1_080_000));
+    }
+
+    #[test]
+    fn high_loss_shrinks_from_what_is_sent() {
+        let mut bwe = with_loss(0.2);
+        assert_eq!(bwe.update(1_000_000), Some(900_000));
+        // The worst loss since the last update counts
+        bwe.on_loss(0.5);
+        bwe.on_loss(0.0);
+        assert_eq!(bwe.update(1_000_000), Some(750_000));
+    }
+
+    #[test]
+    fn remb_caps_the_estimate() {
+        let mut bwe = BandwidthEstimator::default();
+        bwe.on_remb(500_000.0);
+        assert_eq!(bwe.update(1_000_000), Some(500_000));
+        bwe.on_loss(0.0);
+        assert_eq!(bwe.update(400_000), Some(500_000));
+        bwe.on_loss(0.0);
+        assert_eq!(bwe.update(200_000), Some(300_000));
+    }
+
+    #[test]
+    fn twcc_loss_counts_the_packets_without_a_receive_delta() {
+        let mut bwe = BandwidthEstimator::default();
+        bwe.on_twcc(&TransportLayerCc {
+            packet_status_count: 10,
+            recv_deltas: vec![RecvDelta::default(); 8],
+            ..Default::default()
+        });
+        assert_eq!(bwe.update(1_000_000), Some(900_000));
+
+        // Empty feedback says nothing
+        bwe.on_twcc(&TransportLayerCc::default());
+        assert_eq!(bwe.update(2_000_000), Some(900_000));
+    }
+
+    #[test]
+    fn reset_forgets_everything() {
+        let mut bwe = with_loss(0.0);
+        bwe.on_remb(500_000.0);
+        bwe.update(1_000_000);
+        bwe.on_loss(0.5);
+        bwe.reset();
+        assert_eq!(bwe.estimate(), None);
+        assert_eq!(bwe.update(1_000_000), None);
+    }
+}
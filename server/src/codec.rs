@@ -0,0 +1,594 @@
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_H264, MIME_TYPE_VP8, MIME_TYPE_VP9},
+    rtp_transceiver::rtp_codec::RTCRtpCodecCapability,
+};
+
+use crate::bits::BitReader;
+
+/// Whether `offered` is the codec of `expected`, whatever their parameters,
+/// for tracks waiting for a publisher to say which ones it sends
+pub fn is_same_codec(offered: &RTCRtpCodecCapability, expected: &RTCRtpCodecCapability) -> bool {
+    offered.mime_type.eq_ignore_ascii_case(&expected.mime_type)
+        && offered.clock_rate == expected.clock_rate
+}
+
+/// Whether a viewer able to receive `offered` can decode what was `published`
+pub fn is_compatible(offered: &RTCRtpCodecCapability, published: &RTCRtpCodecCapability) -> bool {
+    if !offered.mime_type.eq_ignore_ascii_case(&published.mime_type)
+        || offered.clock_rate != published.clock_rate
+    {
+        return false;
+    }
+
+    let offered_fmtp = |key| fmtp_value(&offered.sdp_fmtp_line, key);
+    let published_fmtp = |key| fmtp_value(&published.sdp_fmtp_line, key);
+    let same = |key, default| {
+        offered_fmtp(key).unwrap_or(default) == published_fmtp(key).unwrap_or(default)
+    };
+    let mime_type = published.mime_type.as_str();
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        // Like webrtc does, only the profile has to match and not the level
+        let profile = |profile_level_id: Option<&str>| {
+            profile_level_id
+                .unwrap_or("420010")
+                .get(..4)
+                .map(str::to_ascii_lowercase)
+        };
+        same("packetization-mode", "0")
+            && profile(offered_fmtp("profile-level-id"))
+                == profile(published_fmtp("profile-level-id"))
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        same("profile-id", "0")
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+        same("profile", "0")
+    } else {
+        true
+    }
+}
+
+/// Value of `key` in an SDP `a=fmtp` parameter list
+fn fmtp_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    line.split(';')
+        .filter_map(|parameter| parameter.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case(key))
+        .map(|(_, value)| value)
+}
+
+/// Whether an RTP payload carries the start of a keyframe
+pub fn is_keyframe(mime_type: &str, payload: &[u8]) -> bool {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        is_h264_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        is_vp8_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        is_vp9_keyframe(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+        is_av1_keyframe(payload)
+    } else {
+        false
+    }
+}
+
+/// RFC 6184: an IDR slice or the SPS sent ahead of it
+fn is_h264_keyframe(payload: &[u8]) -> bool {
+    const IDR: u8 = 5;
+    const SPS: u8 = 7;
+    const STAP_A: u8 = 24;
+    const FU_A: u8 = 28;
+
+    let Some(header) = payload.first() else {
+        return false;
+    };
+    match header & 0x1F {
+        STAP_A => {
+            let mut offset = 1;
+            while offset + 2 < payload.len() {
+                let size = u16::from_be_bytes([payload[offset], payload[offset + 1]]) as usize;
+                if matches!(payload[offset + 2] & 0x1F, IDR | SPS) {
+                    return true;
+                }
+                offset += 2 + size;
+            }
+            false
+        }
+        FU_A => payload
+            .get(1)
+            .is_some_and(|fu_header| fu_header & 0x80 != 0 && fu_header & 0x1F == IDR),
+        nal_type => matches!(nal_type, IDR | SPS),
+    }
+}
+
+/// RFC 7741: first packet of a frame whose payload header has the P bit cleared
+fn is_vp8_keyframe(payload: &[u8]) -> bool {
+    let Some(&descriptor) = payload.first() else {
+        return false;
+    };
+    // Start of partition 0
+    if descriptor & 0x10 == 0 || descriptor & 0x07 != 0 {
+        return false;
+    }
+
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        let Some(&extension) = payload.get(offset) else {
+            return false;
+        };
+        offset += 1;
+        if extension & 0x80 != 0 {
+            // 7 or 15 bits picture id
+            let long = payload.get(offset).is_some_and(|id| id & 0x80 != 0);
+            offset += if long { 2 } else { 1 };
+        }
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    payload
+        .get(offset)
+        .is_some_and(|frame_header| frame_header & 0x01 == 0)
+}
+
+/// RFC 9628: beginning of a frame that is not inter-picture predicted
+fn is_vp9_keyframe(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|descriptor| descriptor & 0x40 == 0 && descriptor & 0x08 != 0)
+}
+
+/// AV1 RTP specification: first packet of a coded video sequence
+fn is_av1_keyframe(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|aggregation_header| aggregation_header & 0x08 != 0)
+}
+
+/// Width and height of the picture a keyframe payload starts, when it says
+pub fn resolution(mime_type: &str, payload: &[u8]) -> Option<(u32, u32)> {
+    if mime_type.eq_ignore_ascii_case(MIME_TYPE_H264) {
+        h264_resolution(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP8) {
+        vp8_resolution(payload)
+    } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+        vp9_resolution(payload)
+    } else {
+        None
+    }
+}
+
+/// From the SPS, alone or in a STAP-A (RFC 6184)
+fn h264_resolution(payload: &[u8]) -> Option<(u32, u32)> {
+    const SPS: u8 = 7;
+    const STAP_A: u8 = 24;
+
+    let sps = match payload.first()? & 0x1F {
+        SPS => payload,
+        STAP_A => {
+            let mut offset = 1;
+            loop {
+                let size = u16::from_be_bytes([*payload.get(offset)?, *payload.get(offset + 1)?]);
+                let nal = payload.get(offset + 2..offset + 2 + usize::from(size))?;
+                if nal.first()? & 0x1F == SPS {
+                    break nal;
+                }
+                offset += 2 + usize::from(size);
+            }
+        }
+        _ => return None,
+    };
+    // Emulation prevention bytes, following two zero bytes of the NAL unit,
+    // are not part of the syntax
+    let mut rbsp = Vec::with_capacity(sps.len());
+    let mut zeros = 0;
+    for &byte in &sps[1..] {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    parse_sps(&mut BitReader::new(&rbsp))
+}
+
+/// ITU-T H.264 7.3.2.1.1, up to the frame cropping
+fn parse_sps(bits: &mut BitReader) -> Option<(u32, u32)> {
+    let profile_idc = bits.read(8)?;
+    bits.read(16)?; // constraint flags and level
+    bits.read_ue()?; // seq_parameter_set_id
+    let mut chroma_format_idc = 1;
+    let mut separate_colour_plane = false;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = bits.read_ue()?;
+        if chroma_format_idc == 3 {
+            separate_colour_plane = bits.read(1)? == 1;
+        }
+        bits.read_ue()?; // bit_depth_luma_minus8
+        bits.read_ue()?; // bit_depth_chroma_minus8
+        bits.read(1)?; // qpprime_y_zero_transform_bypass_flag
+        if bits.read(1)? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for list in 0..lists {
+                if bits.read(1)? == 1 {
+                    skip_scaling_list(bits, if list < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+    bits.read_ue()?; // log2_max_frame_num_minus4
+    match bits.read_ue()? {
+        0 => {
+            bits.read_ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            bits.read(1)?; // delta_pic_order_always_zero_flag
+            bits.read_se()?; // offset_for_non_ref_pic
+            bits.read_se()?; // offset_for_top_to_bottom_field
+            for _ in 0..bits.read_ue()? {
+                bits.read_se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    bits.read_ue()?; // max_num_ref_frames
+    bits.read(1)?; // gaps_in_frame_num_value_allowed_flag
+    // Sizes come from the publisher, overflowing ones are not a picture
+    let width_in_mbs = bits.read_ue()?.checked_add(1)?;
+    let height_in_map_units = bits.read_ue()?.checked_add(1)?;
+    let frame_mbs_only = bits.read(1)?;
+    if frame_mbs_only == 0 {
+        bits.read(1)?; // mb_adaptive_frame_field_flag
+    }
+    bits.read(1)?; // direct_8x8_inference_flag
+    let (mut crop_x, mut crop_y) = (0, 0);
+    if bits.read(1)? == 1 {
+        let (left, right, top, bottom) = (
+            bits.read_ue()?,
+            bits.read_ue()?,
+            bits.read_ue()?,
+            bits.read_ue()?,
+        );
+        let field_factor = 2 - frame_mbs_only;
+        let (unit_x, unit_y) = match chroma_format_idc {
+            _ if separate_colour_plane => (1, field_factor),
+            1 => (2, 2 * field_factor),
+            2 => (2, field_factor),
+            _ => (1, field_factor),
+        };
+        crop_x = left.checked_add(right)?.checked_mul(unit_x)?;
+        crop_y = top.checked_add(bottom)?.checked_mul(unit_y)?;
+    }
+    let width = width_in_mbs.checked_mul(16)?.checked_sub(crop_x)?;
+    let height = height_in_map_units
+        .checked_mul(16 * (2 - frame_mbs_only))?
+        .checked_sub(crop_y)?;
+    Some((width, height))
+}
+
+fn skip_scaling_list(bits: &mut BitReader, size: usize) -> Option<()> {
+    let (mut last_scale, mut next_scale) = (8i64, 8i64);
+    for _ in 0..size {
+        if next_scale != 0 {
+            next_scale = (last_scale + bits.read_se()? + 256) % 256;
+        }
+        if next_scale != 0 {
+            last_scale = next_scale;
+        }
+    }
+    Some(())
+}
+
+/// From the keyframe header after the payload descriptor (RFC 6386 9.1)
+fn vp8_resolution(payload: &[u8]) -> Option<(u32, u32)> {
+    let descriptor = payload.first()?;
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        let extension = payload.get(offset)?;
+        offset += 1;
+        if extension & 0x80 != 0 {
+            offset += if payload.get(offset)? & 0x80 != 0 {
+                2
+            } else {
+                1
+            };
+        }
+        if extension & 0x40 != 0 {
+            offset += 1;
+        }
+        if extension & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    let header = payload.get(offset..offset + 10)?;
+    if header[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from_le_bytes([header[6], header[7]]) & 0x3fff;
+    let height = u16::from_le_bytes([header[8], header[9]]) & 0x3fff;
+    Some((u32::from(width), u32::from(height)))
+}
+
+/// Highest spatial layer of the scalability structure sent with keyframes (RFC 9628)
+fn vp9_resolution(payload: &[u8]) -> Option<(u32, u32)> {
+    let descriptor = payload.first()?;
+    // Scalability structure present
+    if descriptor & 0x02 == 0 {
+        return None;
+    }
+    let mut offset = 1;
+    if descriptor & 0x80 != 0 {
+        offset += if payload.get(offset)? & 0x80 != 0 {
+            2
+        } else {
+            1
+        };
+    }
+    if descriptor & 0x20 != 0 {
+        offset += 1;
+        // Flexible mode has no TL0PICIDX
+        if descriptor & 0x10 == 0 {
+            offset += 1;
+        }
+    }
+    if descriptor & 0x10 != 0 && descriptor & 0x40 != 0 {
+        // Reference indices, each saying whether another follows
+        while payload.get(offset)? & 0x01 != 0 {
+            offset += 1;
+        }
+        offset += 1;
+    }
+    let structure = payload.get(offset)?;
+    // Resolutions present
+    if structure & 0x10 == 0 {
+        return None;
+    }
+    let layers = usize::from(structure >> 5) + 1;
+    let last = payload.get(offset + 1 + (layers - 1) * 4..offset + 1 + layers * 4)?;
+    let width = u16::from_be_bytes([last[0], last[1]]);
+    let height = u16::from_be_bytes([last[2], last[3]]);
+    Some((u32::from(width), u32::from(height)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// x264 High profile 1920x1088 cropped to 1080, with emulation prevention bytes
+    const SPS_1080P: &[u8] = &[
+        0x67, 0x64, 0x00, 0x28, 0xac, 0xd9, 0x40, 0x78, 0x02, 0x27, 0xe5, 0xc0, 0x44, 0x00, 0x00,
+        0x03, 0x00, 0x04, 0x00, 0x00, 0x03, 0x00, 0xf0, 0x3c, 0x60, 0xc6, 0x58,
+    ];
+    /// Constrained baseline 640x480
+    const SPS_480P: &[u8] = &[
+        0x67, 0x42, 0xc0, 0x1f, 0x8c, 0x8d, 0x40, 0x50, 0x1e, 0xd0, 0x0f, 0x08, 0x84, 0x6a,
+    ];
+    /// Constrained baseline 1280x720
+    const SPS_720P: &[u8] = &[0x67, 0x42, 0xe0, 0x1f, 0x95, 0xa0, 0x14, 0x01, 0x6e, 0x40];
+    const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+
+    /// Bytes of a string of bits, padded with zeros
+    fn from_bits(bits: &str) -> Vec<u8> {
+        let bits: Vec<u8> = bits
+            .chars()
+            .filter(|bit| !bit.is_whitespace())
+            .map(|bit| u8::from(bit == '1'))
+            .collect();
+        bits.chunks(8)
+            .map(|byte| {
+                byte.iter()
+                    .chain(std::iter::repeat(&0))
+                    .take(8)
+                    .fold(0, |value, bit| (value << 1) | bit)
+            })
+            .collect()
+    }
+
+    /// A baseline SPS NAL unit with the given Exp-Golomb coded sizes and
+    /// frame cropping, the rest left at its smallest
+    fn baseline_sps(width_in_mbs_minus1: &str, height_minus1: &str, cropping: &str) -> Vec<u8> {
+        let rbsp = [
+            vec![66, 0, 30],
+            from_bits(&format!(
+                // seq_parameter_set_id, log2_max_frame_num_minus4, pic_order_cnt_type,
+                // log2_max_pic_order_cnt_lsb_minus4, max_num_ref_frames, gaps
+                "1 1 1 1 1 0 {width_in_mbs_minus1} {height_minus1} 1 1 {cropping} 1"
+            )),
+        ]
+        .concat();
+        // With the emulation prevention bytes an encoder would add
+        let mut sps = vec![0x67];
+        for byte in rbsp {
+            if byte <= 3 && sps.ends_with(&[0, 0]) {
+                sps.push(3);
+            }
+            sps.push(byte);
+        }
+        sps
+    }
+
+    fn stap_a(nals: &[&[u8]]) -> Vec<u8> {
+        let mut payload = vec![0x78];
+        for nal in nals {
+            payload.extend((nal.len() as u16).to_be_bytes());
+            payload.extend(*nal);
+        }
+        payload
+    }
+
+    #[test]
+    fn h264_sps() {
+        assert_eq!(h264_resolution(SPS_1080P), Some((1920, 1080)));
+        assert_eq!(h264_resolution(SPS_480P), Some((640, 480)));
+        assert_eq!(h264_resolution(SPS_720P), Some((1280, 720)));
+        assert_eq!(resolution("video/h264", SPS_720P), Some((1280, 720)));
+    }
+
+    #[test]
+    fn h264_sps_in_stap_a() {
+        let sei: &[u8] = &[0x06, 0x05, 0x00];
+        assert_eq!(
+            h264_resolution(&stap_a(&[SPS_1080P, PPS])),
+            Some((1920, 1080))
+        );
+        assert_eq!(
+            h264_resolution(&stap_a(&[sei, SPS_480P, PPS])),
+            Some((640, 480))
+        );
+        assert_eq!(h264_resolution(&stap_a(&[sei, PPS])), None);
+    }
+
+    #[test]
+    fn h264_without_sps() {
+        assert_eq!(h264_resolution(PPS), None);
+        assert_eq!(h264_resolution(&[0x65, 0x88, 0x84, 0x00]), None);
+        assert_eq!(h264_resolution(&[]), None);
+    }
+
+    #[test]
+    fn h264_truncated() {
+        for len in 0..9 {
+            assert_eq!(h264_resolution(&SPS_1080P[..len]), None);
+        }
+        for len in 0..SPS_720P.len() - 1 {
+            assert_eq!(h264_resolution(&SPS_720P[..len]), None);
+        }
+        // A STAP-A unit longer than the packet
+        let mut payload = stap_a(&[SPS_720P]);
+        payload.truncate(payload.len() - 1);
+        assert_eq!(h264_resolution(&payload), None);
+        assert_eq!(h264_resolution(&[0x78, 0x00]), None);
+    }
+
+    #[test]
+    fn h264_cropping() {
+        // 2x2 macroblocks cropped by 2 pixels on the right and 4 at the bottom
+        let sps = baseline_sps("010", "010", "1 1 010 1 011");
+        assert_eq!(h264_resolution(&sps), Some((30, 28)));
+        // Cropped beyond the picture
+        let sps = baseline_sps("1", "1", "1 0001001 010 1 1");
+        assert_eq!(h264_resolution(&sps), None);
+    }
+
+    #[test]
+    fn h264_overflowing_sizes() {
+        // 2^32 - 2, the largest Exp-Golomb code read
+        let largest = format!("{}1{}", "0".repeat(31), "1".repeat(31));
+        assert_eq!(h264_resolution(&baseline_sps(&largest, "1", "0")), None);
+        assert_eq!(h264_resolution(&baseline_sps("1", &largest, "0")), None);
+        // Macroblock counts whose size in pixels overflows
+        let huge = format!("{}1{}", "0".repeat(28), "0".repeat(28));
+        assert_eq!(h264_resolution(&baseline_sps(&huge, "1", "0")), None);
+        assert_eq!(h264_resolution(&baseline_sps("1", &huge, "0")), None);
+        // Crop offsets whose sum or scaled size overflows
+        let cropping = format!("1 {largest} {largest} 1 1");
+        assert_eq!(h264_resolution(&baseline_sps("1", "1", &cropping)), None);
+        let cropping = format!("1 1 1 {largest} 1");
+        assert_eq!(h264_resolution(&baseline_sps("1", "1", &cropping)), None);
+        // Longer than any code read
+        let too_long = format!("{}1", "0".repeat(32));
+        assert_eq!(h264_resolution(&baseline_sps(&too_long, "1", "0")), None);
+    }
+
+    /// VP8 keyframe header: frame tag, start code, then sizes with their scaling
+    const VP8_KEYFRAME: &[u8] = &[0x50, 0x42, 0x00, 0x9d, 0x01, 0x2a, 0x80, 0x42, 0xe0, 0x01];
+
+    #[test]
+    fn vp8_keyframe() {
+        let payload = [&[0x10], VP8_KEYFRAME].concat();
+        assert_eq!(vp8_resolution(&payload), Some((640, 480)));
+        assert_eq!(resolution("video/VP8", &payload), Some((640, 480)));
+
+        // Extended descriptor with a long picture id, TL0PICIDX and key index
+        let payload = [&[0x90, 0xe0, 0x81, 0x23, 0x05, 0x20], VP8_KEYFRAME].concat();
+        assert_eq!(vp8_resolution(&payload), Some((640, 480)));
+
+        // Short picture id
+        let payload = [&[0x90, 0x80, 0x12], VP8_KEYFRAME].concat();
+        assert_eq!(vp8_resolution(&payload), Some((640, 480)));
+    }
+
+    #[test]
+    fn vp8_not_a_keyframe_header() {
+        let mut header = VP8_KEYFRAME.to_vec();
+        header[4] = 0x00;
+        assert_eq!(vp8_resolution(&[&[0x10], &header[..]].concat()), None);
+    }
+
+    #[test]
+    fn vp8_truncated() {
+        let payload = [&[0x90, 0xe0, 0x81, 0x23, 0x05, 0x20], VP8_KEYFRAME].concat();
+        for len in 0..payload.len() {
+            assert_eq!(vp8_resolution(&payload[..len]), None);
+        }
+    }
+
+    /// Three spatial layers with their resolutions, 320x180 up to 1280x720
+    const VP9_SCALABILITY_STRUCTURE: &[u8] = &[
+        0x50, 0x01, 0x40, 0x00, 0xb4, 0x02, 0x80, 0x01, 0x68, 0x05, 0x00, 0x02, 0xd0,
+    ];
+
+    #[test]
+    fn vp9_scalability_structure() {
+        // Picture id, start and end of frame, scalability structure
+        let payload = [&[0x8e, 0x12], VP9_SCALABILITY_STRUCTURE].concat();
+        assert_eq!(vp9_resolution(&payload), Some((1280, 720)));
+        assert_eq!(resolution("video/VP9", &payload), Some((1280, 720)));
+
+        // Long picture id, layer indices and TL0PICIDX
+        let payload = [&[0xae, 0x81, 0x23, 0x00, 0x05], VP9_SCALABILITY_STRUCTURE].concat();
+        assert_eq!(vp9_resolution(&payload), Some((1280, 720)));
+
+        // A single layer, in flexible mode with reference indices
+        let payload = [0xfe, 0x12, 0x00, 0x03, 0x02, 0x10, 0x02, 0x80, 0x01, 0x68];
+        assert_eq!(vp9_resolution(&payload), Some((640, 360)));
+    }
+
+    #[test]
+    fn vp9_without_resolutions() {
+        // No scalability structure
+        let payload = [&[0x8c, 0x12], VP9_SCALABILITY_STRUCTURE].concat();
+        assert_eq!(vp9_resolution(&payload), None);
+        // A scalability structure without resolutions
+        assert_eq!(vp9_resolution(&[0x8e, 0x12, 0x40, 0x01]), None);
+    }
+
+    #[test]
+    fn vp9_truncated() {
+        let payload = [&[0xae, 0x81, 0x23, 0x00, 0x05], VP9_SCALABILITY_STRUCTURE].concat();
+        for len in 0..payload.len() {
+            assert_eq!(vp9_resolution(&payload[..len]), None);
+        }
+        // Reference indices running past the end
+        assert_eq!(vp9_resolution(&[0xfe, 0x12, 0x00, 0x03, 0x03]), None);
+    }
+
+    #[test]
+    fn no_resolution_for_other_codecs() {
+        assert_eq!(resolution("video/AV1", SPS_720P), None);
+        assert_eq!(resolution("audio/opus", SPS_720P), None);
+    }
+
+    #[test]
+    fn waiting_codec_ignores_parameters() {
+        let codec = |mime_type: &str, clock_rate, fmtp: &str| RTCRtpCodecCapability {
+            mime_type: mime_type.to_owned(),
+            clock_rate,
+            sdp_fmtp_line: fmtp.to_owned(),
+            ..Default::default()
+        };
+        let waiting = codec(MIME_TYPE_H264, 90000, "");
+        let offered = codec(
+            "video/H264",
+            90000,
+            "packetization-mode=1;profile-level-id=42e01f",
+        );
+        assert!(is_same_codec(&offered, &waiting));
+        assert!(!is_compatible(&offered, &waiting));
+        assert!(!is_same_codec(&codec(MIME_TYPE_VP8, 90000, ""), &waiting));
+    }
+}
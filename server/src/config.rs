@@ -0,0 +1,481 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::Display,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{
+    ice::{self, IceServer, IceServers},
+    network::{NatCandidateType, NetworkConfig},
+    stream::PublisherPolicy,
+};
+
+/// What the secrets are printed as
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Cannot read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("Invalid config file {0}: {1}")]
+    Parse(PathBuf, String),
+    #[error("Invalid {0}: {1}")]
+    Env(&'static str, String),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// Effective server settings, layered from the defaults, the TOML config
+/// file, the environment and the command line flags, each overriding the
+/// previous ones
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses the web server listens on
+    pub bind_addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// UDP port every peer connection shares, an ephemeral one per connection without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp_mux_port: Option<u16>,
+    /// Addresses the UDP mux and ICE-TCP listen on
+    pub udp_mux_addresses: Vec<IpAddr>,
+    /// TCP port of passive ICE-TCP, relayed to the UDP mux
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ice_tcp_port: Option<u16>,
+    /// Directory of the web client
+    pub static_dir: PathBuf,
+    /// Origins allowed to call the API, any without them
+    pub cors_origins: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_keys: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_hmac_secret: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_webhook: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub viewer_policies: Option<PathBuf>,
+    /// H264 keyframe shown to viewers waiting for a publisher
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slate: Option<PathBuf>,
+    pub ice_servers: Vec<IceServer>,
+    pub gop_cache: bool,
+    /// What a second publisher of a live stream gets
+    pub publisher_policy: PublisherPolicy,
+    /// Per stream overrides of the publisher policy
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub publisher_policies: HashMap<String, PublisherPolicy>,
+    /// Seconds a disconnected session is kept
+    pub reap_grace: u64,
+    /// Seconds viewers wait for their publisher to come back
+    pub publisher_grace: u64,
+    pub trickle_answer: bool,
+    /// Seconds ICE gathering may hold an answer back
+    pub gathering_timeout: u64,
+    pub network: NetworkConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addresses: vec![IpAddr::from([0, 0, 0, 0])],
+            port: 8080,
+            udp_mux_port: None,
+            udp_mux_addresses: vec![IpAddr::from([0, 0, 0, 0])],
+            ice_tcp_port: None,
+            static_dir: PathBuf::from("./static"),
+            cors_origins: Vec::new(),
+            auth_keys: None,
+            auth_hmac_secret: None,
+            auth_webhook: None,
+            viewer_policies: None,
+            slate: None,
+            ice_servers: ice::default_servers(),
+            gop_cache: false,
+            publisher_policy: PublisherPolicy::default(),
+            publisher_policies: HashMap::new(),
+            reap_grace: 30,
+            publisher_grace: 30,
+            trickle_answer: false,
+            gathering_timeout: 5,
+            network: NetworkConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Defaults overridden by the TOML file at `path`
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let content =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Read(path.into(), err))?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.into(), err.to_string()))
+    }
+
+    /// Overrides the settings set in the environment, as read by `env`, lists
+    /// being separated by ','
+    pub fn merge_env(&mut self, env: &dyn Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        override_with(&mut self.bind_addresses, env_list(env, "BIND_ADDRESSES")?);
+        override_with(&mut self.port, env_value(env, "PORT")?);
+        override_with(
+            &mut self.udp_mux_port,
+            env_value(env, "UDP_MUX_PORT")?.map(Some),
+        );
+        override_with(
+            &mut self.udp_mux_addresses,
+            env_list(env, "UDP_MUX_ADDRESSES")?,
+        );
+        override_with(
+            &mut self.ice_tcp_port,
+            env_value(env, "ICE_TCP_PORT")?.map(Some),
+        );
+        override_with(&mut self.static_dir, env_value(env, "STATIC_DIR")?);
+        override_with(&mut self.cors_origins, env_list(env, "CORS_ORIGINS")?);
+        override_with(&mut self.auth_keys, env_value(env, "AUTH_KEYS")?.map(Some));
+        override_with(
+            &mut self.auth_hmac_secret,
+            env_value(env, "AUTH_HMAC_SECRET")?.map(Some),
+        );
+        override_with(
+            &mut self.auth_webhook,
+            env_value(env, "AUTH_WEBHOOK")?.map(Some),
+        );
+        override_with(
+            &mut self.viewer_policies,
+            env_value(env, "VIEWER_POLICIES")?.map(Some),
+        );
+        override_with(&mut self.slate, env_value(env, "SLATE")?.map(Some));
+        if let Some(path) = env_value::<PathBuf>(env, "ICE_SERVERS")? {
+            self.ice_servers = load_ice_servers(&path)?;
+        }
+        override_with(&mut self.gop_cache, env_value(env, "GOP_CACHE")?);
+        override_with(
+            &mut self.publisher_policy,
+            env_value(env, "PUBLISHER_POLICY")?,
+        );
+        override_with(&mut self.reap_grace, env_value(env, "REAP_GRACE")?);
+        override_with(
+            &mut self.publisher_grace,
+            env_value(env, "PUBLISHER_GRACE")?,
+        );
+        override_with(&mut self.trickle_answer, env_value(env, "TRICKLE_ANSWER")?);
+        override_with(
+            &mut self.gathering_timeout,
+            env_value(env, "GATHERING_TIMEOUT")?,
+        );
+
+        let network = &mut self.network;
+        override_with(&mut network.nat_ips, env_list(env, "NAT_IPS")?);
+        override_with(
+            &mut network.nat_candidate_type,
+            env_value(env, "NAT_CANDIDATE_TYPE")?,
+        );
+        override_with(&mut network.interfaces, env_list(env, "INTERFACES")?);
+        override_with(
+            &mut network.deny_interfaces,
+            env_list(env, "DENY_INTERFACES")?,
+        );
+        override_with(&mut network.allow_ips, env_list(env, "ALLOW_IPS")?);
+        override_with(&mut network.deny_ips, env_list(env, "DENY_IPS")?);
+        override_with(&mut network.ipv4, env_value(env, "IPV4")?);
+        override_with(&mut network.ipv6, env_value(env, "IPV6")?);
+        override_with(
+            &mut network.udp_port_range,
+            env_value(env, "UDP_PORT_RANGE")?.map(Some),
+        );
+        Ok(())
+    }
+
+    /// Replaces the secrets with a placeholder, for the configuration to be
+    /// printed where others may read it
+    pub fn redact(&mut self) {
+        for secret in [&mut self.auth_hmac_secret, &mut self.auth_webhook]
+            .into_iter()
+            .flatten()
+        {
+            *secret = REDACTED.to_string();
+        }
+        for server in &mut self.ice_servers {
+            server.redact(REDACTED);
+        }
+    }
+
+    /// Checks the settings fit together, before anything starts
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |message: String| Err(ConfigError::Invalid(message));
+        let auth_methods = [
+            self.auth_keys.is_some(),
+            self.auth_hmac_secret.is_some(),
+            self.auth_webhook.is_some(),
+        ];
+        if auth_methods.into_iter().filter(|set| *set).count() > 1 {
+            return invalid(
+                "only one of auth keys, auth hmac secret and auth webhook can be set".to_string(),
+            );
+        }
+        for path in [&self.auth_keys, &self.viewer_policies, &self.slate]
+            .into_iter()
+            .flatten()
+        {
+            if !path.is_file() {
+                return invalid(format!("{} is not a file", path.display()));
+            }
+        }
+        if !self.static_dir.is_dir() {
+            return invalid(format!(
+                "static dir {} is not a directory",
+                self.static_dir.display()
+            ));
+        }
+        if let Some(origin) = self
+            .cors_origins
+            .iter()
+            .find(|origin| !origin.starts_with("http://") && !origin.starts_with("https://"))
+        {
+            return invalid(format!("CORS origin '{origin}' is not an http(s) origin"));
+        }
+        if self.bind_addresses.is_empty() {
+            return invalid("the web server needs a bind address".to_string());
+        }
+        let network = &self.network;
+        if self.udp_mux_port.is_some()
+            && !self.udp_mux_addresses.iter().any(|ip| {
+                if ip.is_ipv4() {
+                    network.ipv4
+                } else {
+                    network.ipv6
+                }
+            })
+        {
+            return invalid("the UDP mux needs an address of an enabled IP family".to_string());
+        }
+        if self.udp_mux_port == Some(0) {
+            return invalid("the UDP mux port cannot be 0".to_string());
+        }
+        match self.ice_tcp_port {
+            Some(0) => return invalid("the ICE-TCP port cannot be 0".to_string()),
+            Some(_) if self.udp_mux_port.is_none() => {
+                return invalid("ICE-TCP needs the UDP mux port".to_string());
+            }
+            _ => {}
+        }
+        if self.gathering_timeout == 0 {
+            return invalid("the gathering timeout must be at least a second".to_string());
+        }
+        // The UDP mux has no per connection socket to map server reflexive candidates from
+        if self.udp_mux_port.is_some()
+            && !self.network.nat_ips.is_empty()
+            && self.network.nat_candidate_type == NatCandidateType::Srflx
+        {
+            return invalid("srflx NAT ips cannot be used with the UDP mux".to_string());
+        }
+        self.network.validate().or_else(invalid)?;
+        IceServers::validate(&self.ice_servers).or_else(invalid)
+    }
+}
+
+/// Contents of a TOML file, or of a JSON one with the `.json` extension
+pub fn load_file<T: DeserializeOwned>(path: &Path) -> std::io::Result<T> {
+    let content = std::fs::read_to_string(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => serde_json::from_str(&content).map_err(std::io::Error::other),
+        _ => toml::from_str(&content).map_err(std::io::Error::other),
+    }
+}
+
+/// The STUN and TURN servers of a TOML or JSON file
+pub fn load_ice_servers(path: &Path) -> Result<Vec<IceServer>, ConfigError> {
+    IceServers::load(path).map_err(|err| ConfigError::Parse(path.into(), err.to_string()))
+}
+
+pub fn override_with<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+/// Value of a set, non empty environment variable
+pub fn non_empty_env(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+fn env_value<T: FromStr>(
+    env: &dyn Fn(&str) -> Option<String>,
+    key: &'static str,
+) -> Result<Option<T>, ConfigError>
+where
+    T::Err: Display,
+{
+    env(key)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|err: T::Err| ConfigError::Env(key, format!("'{value}': {err}")))
+        })
+        .transpose()
+}
+
+fn env_list<T: FromStr>(
+    env: &dyn Fn(&str) -> Option<String>,
+    key: &'static str,
+) -> Result<Option<Vec<T>>, ConfigError>
+where
+    T::Err: Display,
+{
+    env(key)
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| {
+                    item.parse()
+                        .map_err(|err: T::Err| ConfigError::Env(key, format!("'{item}': {err}")))
+                })
+                .collect()
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    fn invalid(config: &Config) -> String {
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => message,
+            other => panic!("expected an invalid configuration, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn environment_overrides_the_defaults() {
+        let mut config = Config::default();
+        config
+            .merge_env(&env(&[
+                ("PORT", "9000"),
+                ("GOP_CACHE", "true"),
+                ("CORS_ORIGINS", "https://a.example, https://b.example,"),
+                ("IPV6", "false"),
+            ]))
+            .unwrap();
+        assert_eq!(config.port, 9000);
+        assert!(config.gop_cache);
+        assert_eq!(
+            config.cors_origins,
+            vec!["https://a.example", "https://b.example"]
+        );
+        assert!(!config.network.ipv6);
+        assert!(!config.trickle_answer);
+    }
+
+    #[test]
+    fn invalid_environment_names_the_variable() {
+        let mut config = Config::default();
+        let err = config.merge_env(&env(&[("GOP_CACHE", "yes")])).unwrap_err();
+        assert!(matches!(err, ConfigError::Env("GOP_CACHE", _)));
+        let err = config
+            .merge_env(&env(&[("BIND_ADDRESSES", "0.0.0.0,localhost")]))
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::Env("BIND_ADDRESSES", _)));
+    }
+
+    #[test]
+    fn printed_configuration_has_no_secrets() {
+        let mut config: Config = toml::from_str(
+            r#"
+            auth_hmac_secret = "hmac-s3cret"
+
+            [[ice_servers]]
+            urls = ["turn:turn.example.com:3478"]
+            username = "omniroom"
+            credential = "turn-s3cret"
+
+            [[ice_servers]]
+            urls = ["turns:turn.example.com:5349"]
+            secret = "rest-s3cret"
+            "#,
+        )
+        .unwrap();
+        config.redact();
+        let printed = toml::to_string(&config).unwrap();
+        for secret in ["hmac-s3cret", "turn-s3cret", "rest-s3cret"] {
+            assert!(!printed.contains(secret), "{secret} in {printed}");
+        }
+        assert!(printed.contains("username = \"omniroom\""));
+
+        let mut config = Config {
+            auth_webhook: Some("https://auth.example.com/?key=webhook-s3cret".to_string()),
+            ..Config::default()
+        };
+        config.redact();
+        assert!(!toml::to_string(&config).unwrap().contains("webhook-s3cret"));
+    }
+
+    #[test]
+    fn validation_errors() {
+        let valid = || Config {
+            static_dir: env::temp_dir(),
+            ..Config::default()
+        };
+        valid().validate().unwrap();
+
+        let config = Config {
+            auth_keys: Some(PathBuf::from("keys.toml")),
+            auth_hmac_secret: Some("s3cret".to_string()),
+            ..valid()
+        };
+        assert!(invalid(&config).contains("only one of"));
+
+        let config = Config {
+            slate: Some(env::temp_dir()),
+            ..valid()
+        };
+        assert!(invalid(&config).ends_with("is not a file"));
+
+        let config = Config {
+            static_dir: PathBuf::from("/nonexistent/omniroom"),
+            ..Config::default()
+        };
+        assert!(invalid(&config).starts_with("static dir"));
+
+        let config = Config {
+            cors_origins: vec!["example.com".to_string()],
+            ..valid()
+        };
+        assert!(invalid(&config).contains("not an http(s) origin"));
+
+        let config = Config {
+            ice_tcp_port: Some(4443),
+            ..valid()
+        };
+        assert_eq!(invalid(&config), "ICE-TCP needs the UDP mux port");
+
+        let mut config = Config {
+            udp_mux_port: Some(3478),
+            ..valid()
+        };
+        config.network.ipv4 = false;
+        assert!(invalid(&config).contains("enabled IP family"));
+
+        let config = Config {
+            gathering_timeout: 0,
+            ..valid()
+        };
+        assert!(invalid(&config).contains("gathering timeout"));
+    }
+}
@@ -0,0 +1,214 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::broadcast::{self, error::RecvError},
+    task::JoinHandle,
+};
+use webrtc::{rtp::packet::Packet, track::track_local::TrackLocalWriter};
+
+/// How many packets a viewer may lag behind before it starts losing some
+pub const FANOUT_CAPACITY: usize = 1024;
+
+/// One publisher track fanned out to any number of viewer tracks
+///
+/// Publishing never waits on viewers: each viewer forwards from its own
+/// receiver, so a viewer too slow to keep up only drops its own packets,
+/// skipping ahead to the oldest one kept and flagging the fanout as lagged.
+#[derive(Clone)]
+pub struct Fanout {
+    sender: broadcast::Sender<Arc<Packet>>,
+    /// Whether a receiver skipped packets, its viewer needing a keyframe
+    lagged: Arc<AtomicBool>,
+}
+
+impl Default for Fanout {
+    fn default() -> Self {
+        Self::new(FANOUT_CAPACITY)
+    }
+}
+
+impl Fanout {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self {
+            sender,
+            lagged: Default::default(),
+        }
+    }
+
+    pub fn publish(&self, packet: impl Into<Arc<Packet>>) {
+        // Nobody watching is not an error
+        let _ = self.sender.send(packet.into());
+    }
+
+    /// Every packet published from now on, for callers forwarding themselves
+    pub fn receiver(&self) -> FanoutReceiver {
+        FanoutReceiver {
+            receiver: self.sender.subscribe(),
+            lagged: self.lagged.clone(),
+        }
+    }
+
+    /// Whether a receiver skipped packets since the last call
+    pub fn take_lagged(&self) -> bool {
+        self.lagged.swap(false, Ordering::Relaxed)
+    }
+
+    /// Spawns a task writing every published packet to `writer` until aborted
+    /// or the fanout is dropped
+    pub fn subscribe<W>(&self, writer: Arc<W>) -> JoinHandle<()>
+    where
+        W: TrackLocalWriter + Send + Sync + 'static,
+    {
+        self.subscribe_after(writer, Vec::new())
+    }
+
+    /// Like [`Fanout::subscribe`], writing `backlog` first
+    pub fn subscribe_after<W>(&self, writer: Arc<W>, backlog: Vec<Arc<Packet>>) -> JoinHandle<()>
+    where
+        W: TrackLocalWriter + Send + Sync + 'static,
+    {
+        forward(writer, backlog, self.receiver())
+    }
+}
+
+/// Where a forwarding task takes the packets it writes from
+#[async_trait]
+pub trait PacketSource: Send + 'static {
+    /// The next packet to write, none once there are no more
+    async fn next_packet(&mut self) -> Option<Arc<Packet>>;
+}
+
+/// Packets published on a [`Fanout`], in order
+pub struct FanoutReceiver {
+    receiver: broadcast::Receiver<Arc<Packet>>,
+    lagged: Arc<AtomicBool>,
+}
+
+#[async_trait]
+impl PacketSource for FanoutReceiver {
+    async fn next_packet(&mut self) -> Option<Arc<Packet>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(packet) => return Some(packet),
+                Err(RecvError::Lagged(_)) => self.lagged.store(true, Ordering::Relaxed),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Spawns a task writing `backlog`, then every packet of `source`, to
+/// `writer` until aborted or the source runs out
+pub fn forward<W, S>(writer: Arc<W>, backlog: Vec<Arc<Packet>>, mut source: S) -> JoinHandle<()>
+where
+    W: TrackLocalWriter + Send + Sync + 'static,
+    S: PacketSource,
+{
+    tokio::spawn(async move {
+        let mut backlog = backlog.into_iter();
+        while let Some(packet) = match backlog.next() {
+            Some(packet) => Some(packet),
+            None => source.next_packet().await,
+        } {
+            // A failing viewer is torn down by its session, keep going until then
+            let _ = writer.write_rtp(&packet).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Duration};
+
+    use webrtc::{interceptor::Attributes, rtp::header::Header};
+
+    use super::*;
+
+    /// A viewer track keeping the sequence numbers it is given
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<u16>>);
+
+    #[async_trait]
+    impl TrackLocalWriter for Recorder {
+        async fn write_rtp_with_attributes(
+            &self,
+            packet: &Packet,
+            _attr: &Attributes,
+        ) -> webrtc::error::Result<usize> {
+            self.0.lock().unwrap().push(packet.header.sequence_number);
+            Ok(packet.payload.len())
+        }
+    }
+
+    fn packet(sequence_number: u16) -> Packet {
+        Packet {
+            header: Header {
+                sequence_number,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    async fn next(receiver: &mut FanoutReceiver) -> u16 {
+        receiver.next_packet().await.unwrap().header.sequence_number
+    }
+
+    #[tokio::test]
+    async fn slow_receiver_skips_ahead_without_holding_back_the_others() {
+        let fanout = Fanout::new(4);
+        let mut fast = fanout.receiver();
+        let mut slow = fanout.receiver();
+        for sequence_number in 0..10 {
+            fanout.publish(packet(sequence_number));
+            assert_eq!(next(&mut fast).await, sequence_number);
+        }
+        assert!(!fanout.take_lagged());
+
+        // Only the last packets are kept for it
+        assert_eq!(next(&mut slow).await, 6);
+        assert!(fanout.take_lagged());
+        assert!(!fanout.take_lagged());
+        for expected in 7..10 {
+            assert_eq!(next(&mut slow).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn forwarding_ends_with_the_fanout() {
+        let fanout = Fanout::default();
+        let recorder = Arc::new(Recorder::default());
+        let task = fanout.subscribe_after(recorder.clone(), vec![Arc::new(packet(1))]);
+        fanout.publish(packet(2));
+        fanout.publish(packet(3));
+        drop(fanout);
+
+        tokio::time::timeout(Duration::from_secs(5), task)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn fanouts_are_isolated() {
+        let (high, low) = (Fanout::new(2), Fanout::new(2));
+        let mut high_receiver = high.receiver();
+        let mut low_receiver = low.receiver();
+        for sequence_number in 0..4 {
+            high.publish(packet(sequence_number));
+        }
+        low.publish(packet(100));
+
+        assert_eq!(next(&mut low_receiver).await, 100);
+        assert!(!low.take_lagged());
+        assert_eq!(next(&mut high_receiver).await, 2);
+        assert!(high.take_lagged());
+        assert!(!low.take_lagged());
+    }
+}
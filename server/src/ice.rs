@@ -0,0 +1,258 @@
+use std::path::Path;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+
+use crate::{auth::unix_now, config};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// STUN server used when none is configured
+const DEFAULT_STUN_SERVER: &str = "stun:stun.l.google.com:19302";
+
+/// A STUN or TURN server
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    /// Static TURN credentials
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    credential: Option<String>,
+    /// Secret shared with the TURN server to hand out expiring credentials,
+    /// see [`IceServers::for_session`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+    /// How many seconds the expiring credentials are valid
+    #[serde(default = "default_ttl")]
+    ttl: u64,
+}
+
+fn default_ttl() -> u64 {
+    86400
+}
+
+impl IceServer {
+    fn validate(&self) -> Result<(), String> {
+        if self.urls.is_empty() {
+            return Err("an ICE server needs at least one url".to_string());
+        }
+        if let Some(url) = self.urls.iter().find(|url| {
+            !["stun:", "stuns:", "turn:", "turns:"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme))
+        }) {
+            return Err(format!("'{url}' is not a STUN or TURN url"));
+        }
+        let credentials = self.username.is_some() && self.credential.is_some();
+        if self.is_turn() && self.secret.is_none() && !credentials {
+            return Err(format!(
+                "TURN server {} needs a username and credential, or a secret",
+                self.urls[0]
+            ));
+        }
+        Ok(())
+    }
+
+    /// Replaces the credentials and the secret with `placeholder`
+    pub fn redact(&mut self, placeholder: &str) {
+        for secret in [&mut self.credential, &mut self.secret]
+            .into_iter()
+            .flatten()
+        {
+            *secret = placeholder.to_string();
+        }
+    }
+
+    fn is_turn(&self) -> bool {
+        self.urls
+            .iter()
+            .any(|url| url.starts_with("turn:") || url.starts_with("turns:"))
+    }
+
+    /// Credentials for `user`: static ones, or TURN REST API ones where the
+    /// username is `<expires>:<user>` and the credential the base64 encoded
+    /// HMAC-SHA1 of the username
+    fn credentials(&self, user: &str) -> Option<(String, String)> {
+        match &self.secret {
+            Some(secret) => {
+                let username = format!("{}:{user}", unix_now() + self.ttl);
+                let credential = turn_credential(secret, &username);
+                Some((username, credential))
+            }
+            None => Some((self.username.clone()?, self.credential.clone()?)),
+        }
+    }
+}
+
+/// TURN REST API password of `username`
+fn turn_credential(secret: &str, username: &str) -> String {
+    let mut mac =
+        HmacSha1::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(username.as_bytes());
+    STANDARD.encode(mac.finalize().into_bytes())
+}
+
+/// STUN and TURN servers advertised to clients, the STUN ones being used by
+/// the server too
+#[derive(Clone, Debug, Deserialize)]
+pub struct IceServers {
+    servers: Vec<IceServer>,
+}
+
+/// The default STUN server
+pub fn default_servers() -> Vec<IceServer> {
+    vec![IceServer {
+        urls: vec![DEFAULT_STUN_SERVER.to_owned()],
+        username: None,
+        credential: None,
+        secret: None,
+        ttl: default_ttl(),
+    }]
+}
+
+impl IceServers {
+    pub fn new(servers: Vec<IceServer>) -> Self {
+        Self { servers }
+    }
+
+    /// Servers listed under `[[servers]]` in a TOML or JSON file
+    pub fn load(path: &Path) -> std::io::Result<Vec<IceServer>> {
+        let servers: Self = config::load_file(path)?;
+        Ok(servers.servers)
+    }
+
+    pub fn validate(servers: &[IceServer]) -> Result<(), String> {
+        servers.iter().try_for_each(IceServer::validate)
+    }
+
+    /// Servers the server gathers its own candidates with, relaying through
+    /// TURN would only add a hop
+    pub fn stun_servers(&self) -> Vec<RTCIceServer> {
+        self.servers
+            .iter()
+            .filter(|server| !server.is_turn())
+            .map(|server| RTCIceServer {
+                urls: server.urls.clone(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Servers for the client of `session`, with its own TURN credentials
+    pub fn for_session(&self, session: &str) -> Vec<RTCIceServer> {
+        self.servers
+            .iter()
+            .map(|server| {
+                let (username, credential) = server.credentials(session).unzip();
+                RTCIceServer {
+                    urls: server.urls.clone(),
+                    username: username.unwrap_or_default(),
+                    credential: credential.unwrap_or_default(),
+                }
+            })
+            .collect()
+    }
+
+    /// `Link` header values advertising the servers of `session` (RFC 9725)
+    pub fn links(&self, session: &str) -> Vec<String> {
+        ice_server_links(&self.for_session(session))
+    }
+
+    /// `Link` header values advertising the STUN servers, for anyone asking
+    /// before being authorized, TURN credentials being given with answers only
+    pub fn stun_links(&self) -> Vec<String> {
+        ice_server_links(&self.stun_servers())
+    }
+}
+
+fn ice_server_links(servers: &[RTCIceServer]) -> Vec<String> {
+    let mut links = Vec::new();
+    for server in servers {
+        for url in &server.urls {
+            let mut link = format!("<{url}>; rel=\"ice-server\"");
+            if !server.username.is_empty() {
+                link.push_str(&format!(
+                    "; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                    server.username, server.credential
+                ));
+            }
+            links.push(link);
+        }
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers() -> IceServers {
+        toml::from_str(
+            r#"
+            [[servers]]
+            urls = ["stun:stun.example.com:3478"]
+
+            [[servers]]
+            urls = ["turn:turn.example.com:3478"]
+            username = "omniroom"
+            credential = "s3cret"
+
+            [[servers]]
+            urls = ["turns:turn.example.com:5349"]
+            secret = "secret"
+            ttl = 3600
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn turn_credential_is_the_base64_hmac_sha1_of_the_username() {
+        assert_eq!(
+            turn_credential("secret", "1700000000:session"),
+            "idoNDFBf2sy7Bnutp1pL0h6atIY="
+        );
+    }
+
+    #[test]
+    fn expiring_credentials_name_the_session() {
+        let before = unix_now();
+        let servers = servers().for_session("session");
+        let after = unix_now();
+
+        let server = &servers[2];
+        let (expires, user) = server.username.split_once(':').unwrap();
+        let expires: u64 = expires.parse().unwrap();
+        assert_eq!(user, "session");
+        assert!((before + 3600..=after + 3600).contains(&expires));
+        assert_eq!(
+            server.credential,
+            turn_credential("secret", &server.username)
+        );
+    }
+
+    #[test]
+    fn static_credentials_are_kept() {
+        let servers = servers().for_session("session");
+        assert_eq!(servers[1].username, "omniroom");
+        assert_eq!(servers[1].credential, "s3cret");
+        assert!(servers[0].username.is_empty());
+    }
+
+    #[test]
+    fn stun_links_carry_no_credentials() {
+        assert_eq!(
+            servers().stun_links(),
+            vec!["<stun:stun.example.com:3478>; rel=\"ice-server\"".to_string()]
+        );
+        let links = servers().links("session");
+        assert_eq!(links.len(), 3);
+        assert!(links[2].contains("username=\""));
+        assert!(links[2].contains("credential-type=\"password\""));
+    }
+}
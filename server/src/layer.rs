@@ -0,0 +1,412 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use tokio::task::JoinHandle;
+use webrtc::{
+    interceptor::Attributes,
+    rtp::packet::Packet,
+    track::track_local::{TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
+};
+
+use crate::{
+    bwe::BitrateMeter,
+    codec,
+    fanout::{Fanout, FanoutReceiver},
+    retransmit::RetransmitBuffer,
+    rewrite::RtpRewriter,
+    svc::{SvcLayer, SvcPacket, SvcParser},
+};
+
+/// Upper bound of a cached group of pictures, caching stops until the next keyframe past it
+const MAX_GOP_PACKETS: usize = 4096;
+
+/// Video packets since the last keyframe, replayed to new viewers
+#[derive(Default)]
+struct GopCache {
+    packets: Vec<Arc<Packet>>,
+}
+
+impl GopCache {
+    fn push(&mut self, packet: &Arc<Packet>, keyframe: bool) {
+        // Parameter sets and the keyframe itself share a timestamp
+        let new_picture = self
+            .packets
+            .first()
+            .is_none_or(|first| first.header.timestamp != packet.header.timestamp);
+        if keyframe && new_picture {
+            self.packets.clear();
+        }
+        if self.packets.is_empty() && !keyframe {
+            return;
+        }
+        if self.packets.len() >= MAX_GOP_PACKETS {
+            self.packets.clear();
+            return;
+        }
+        self.packets.push(packet.clone());
+    }
+}
+
+/// One encoding of a published track: a simulcast layer, or the whole track
+/// when it is not simulcast
+pub struct Layer {
+    /// RTP stream id of the encoding, empty without simulcast
+    pub rid: String,
+    ssrc: AtomicU32,
+    fanout: Fanout,
+    pub bitrate: BitrateMeter,
+    pub retransmit: std::sync::Mutex<RetransmitBuffer>,
+    /// Spatial and temporal layers within the encoding, for VP9 and AV1
+    pub svc: Arc<RwLock<SvcParser>>,
+    gop: Option<std::sync::Mutex<GopCache>>,
+    /// Width and height of the last keyframe that said
+    resolution: std::sync::Mutex<Option<(u32, u32)>>,
+}
+
+impl Layer {
+    pub fn new(rid: String, gop_cache: bool) -> Self {
+        Self {
+            rid,
+            ssrc: AtomicU32::new(0),
+            fanout: Fanout::default(),
+            bitrate: BitrateMeter::default(),
+            retransmit: Default::default(),
+            svc: Default::default(),
+            gop: gop_cache.then(Default::default),
+            resolution: Default::default(),
+        }
+    }
+
+    /// SSRC the publisher sends the layer with, once it started
+    pub fn ssrc(&self) -> Option<u32> {
+        Some(self.ssrc.load(Ordering::Relaxed)).filter(|&ssrc| ssrc != 0)
+    }
+
+    /// Binds the layer to a new source, whose pictures cannot follow the cached ones
+    pub fn set_ssrc(&self, ssrc: u32) {
+        if self.ssrc.swap(ssrc, Ordering::Relaxed) == ssrc {
+            return;
+        }
+        *self.resolution.lock().unwrap() = None;
+        if let Some(gop) = &self.gop {
+            gop.lock().unwrap().packets.clear();
+        }
+    }
+
+    pub fn resolution(&self) -> Option<(u32, u32)> {
+        *self.resolution.lock().unwrap()
+    }
+
+    pub fn set_resolution(&self, resolution: (u32, u32)) {
+        *self.resolution.lock().unwrap() = Some(resolution);
+    }
+
+    pub fn publish(&self, packet: Packet, keyframe: bool) {
+        // Left over from a publisher that was taken over
+        if self.ssrc() != Some(packet.header.ssrc) {
+            return;
+        }
+        let packet = Arc::new(packet);
+        self.bitrate.add(packet.payload.len());
+        self.svc.write().unwrap().observe(&packet);
+        self.retransmit.lock().unwrap().push(&packet);
+        match &self.gop {
+            // Cache and send together so a joining viewer sees each packet exactly once
+            Some(gop) => {
+                let mut gop = gop.lock().unwrap();
+                gop.push(&packet, keyframe);
+                self.fanout.publish(packet);
+            }
+            None => self.fanout.publish(packet),
+        }
+    }
+
+    /// Whether a viewer fell behind and skipped packets since the last call
+    pub fn take_lagged(&self) -> bool {
+        self.fanout.take_lagged()
+    }
+
+    /// Spawns a task forwarding the layer to `writer`, from the cached group
+    /// of pictures
+    pub fn subscribe(&self, writer: Arc<ForwardingWriter>) -> JoinHandle<()> {
+        match &self.gop {
+            Some(gop) => {
+                let gop = gop.lock().unwrap();
+                self.fanout.subscribe_after(writer, gop.packets.clone())
+            }
+            None => self.fanout.subscribe(writer),
+        }
+    }
+
+    /// Packets published from now on, after the cached group of pictures
+    pub fn receiver(&self) -> (FanoutReceiver, Vec<Arc<Packet>>) {
+        match &self.gop {
+            Some(gop) => {
+                let gop = gop.lock().unwrap();
+                (self.fanout.receiver(), gop.packets.clone())
+            }
+            None => (self.fanout.receiver(), Vec::new()),
+        }
+    }
+}
+
+/// Which layer of a track a viewer gets, and how it is renumbered
+#[derive(Debug)]
+pub struct Forwarding {
+    pub layer: usize,
+    pub rewriter: RtpRewriter,
+    /// Nothing is sent while paused
+    paused: bool,
+    /// Resumed, waiting for a keyframe to start decoding from
+    resuming: bool,
+    /// Highest spatial and temporal layers sent, all of them without one
+    svc_target: Option<SvcLayer>,
+    /// Target taking over at the next picture starting on the base layers
+    pending_svc_target: Option<Option<SvcLayer>>,
+}
+
+impl Forwarding {
+    fn new(layer: usize, rewriter: RtpRewriter) -> Self {
+        Self {
+            layer,
+            rewriter,
+            paused: false,
+            resuming: false,
+            svc_target: None,
+            pending_svc_target: None,
+        }
+    }
+
+    /// Renumbers a packet to send, or leaves it out when above the SVC target
+    fn forward<'a>(
+        &mut self,
+        packet: &'a Packet,
+        keyframe: bool,
+        svc: Option<SvcPacket>,
+    ) -> Option<Cow<'a, Packet>> {
+        if self.paused || (self.resuming && !keyframe) {
+            return None;
+        }
+        self.resuming = false;
+        let Some(svc) = svc else {
+            return Some(self.rewriter.rewrite(packet));
+        };
+        // Layers only change where decoding can carry on
+        if svc.start_of_frame
+            && svc.layer.spatial == 0
+            && svc.layer.temporal == 0
+            && let Some(target) = self.pending_svc_target.take()
+        {
+            self.svc_target = target;
+        }
+        let Some(target) = self.svc_target else {
+            return Some(self.rewriter.rewrite(packet));
+        };
+        if !svc.layer.is_within(&target) {
+            self.rewriter.skip();
+            return None;
+        }
+        let mut packet = self.rewriter.rewrite(packet);
+        // The picture now ends with the highest spatial layer sent
+        if svc.end_of_frame && svc.layer.spatial == target.spatial {
+            packet.to_mut().header.marker = true;
+        }
+        Some(packet)
+    }
+}
+
+/// A viewer's track, renumbering what is written to it
+///
+/// Locks are taken in this order: `forwarding`, then a layer's `svc`, then its
+/// `retransmit` buffer. None of them is held across an await.
+#[derive(Debug)]
+pub struct ForwardingWriter {
+    pub local: Arc<TrackLocalStaticRTP>,
+    pub forwarding: std::sync::Mutex<Forwarding>,
+    mime_type: String,
+    /// SVC parsers of the track's layers, by layer
+    svc: Vec<Arc<RwLock<SvcParser>>>,
+}
+
+impl ForwardingWriter {
+    pub fn new(
+        local: Arc<TrackLocalStaticRTP>,
+        layers: &[Layer],
+        layer: usize,
+        mime_type: String,
+        clock_rate: u32,
+    ) -> Self {
+        Self {
+            local,
+            forwarding: std::sync::Mutex::new(Forwarding::new(layer, RtpRewriter::new(clock_rate))),
+            mime_type,
+            svc: layers.iter().map(|layer| layer.svc.clone()).collect(),
+        }
+    }
+
+    /// A writer to the same viewer track from the `layers` of another
+    /// published track, continuing its numbering
+    pub fn rebind(&self, layers: &[Layer], layer: usize) -> Self {
+        let rewriter = self.forwarding.lock().unwrap().rewriter.clone();
+        Self {
+            local: self.local.clone(),
+            forwarding: std::sync::Mutex::new(Forwarding::new(layer, rewriter)),
+            mime_type: self.mime_type.clone(),
+            svc: layers.iter().map(|layer| layer.svc.clone()).collect(),
+        }
+    }
+
+    pub fn layer(&self) -> usize {
+        self.forwarding.lock().unwrap().layer
+    }
+
+    /// Continues the track with `layer`, from the next packet written
+    pub fn switch(&self, layer: usize) {
+        let mut forwarding = self.forwarding.lock().unwrap();
+        forwarding.layer = layer;
+        forwarding.rewriter.rebase();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.forwarding.lock().unwrap().paused
+    }
+
+    /// Stops sending until resumed
+    pub fn pause(&self) {
+        self.forwarding.lock().unwrap().paused = true;
+    }
+
+    /// Sends again from the next keyframe, continuing the numbering
+    pub fn resume(&self) {
+        let mut forwarding = self.forwarding.lock().unwrap();
+        forwarding.paused = false;
+        forwarding.resuming = true;
+        forwarding.rewriter.rebase();
+    }
+
+    /// SVC target the viewer is moving to, or currently gets
+    pub fn svc_target(&self) -> Option<SvcLayer> {
+        let forwarding = self.forwarding.lock().unwrap();
+        forwarding
+            .pending_svc_target
+            .unwrap_or(forwarding.svc_target)
+    }
+
+    /// Sends up to the `target` SVC layers from the next picture starting on
+    /// the base layers, returns whether it changed
+    pub fn set_svc_target(&self, target: Option<SvcLayer>) -> bool {
+        let mut forwarding = self.forwarding.lock().unwrap();
+        let changed = forwarding
+            .pending_svc_target
+            .unwrap_or(forwarding.svc_target)
+            != target;
+        if changed {
+            forwarding.pending_svc_target = Some(target);
+        }
+        changed
+    }
+}
+
+#[async_trait]
+impl TrackLocalWriter for ForwardingWriter {
+    async fn write_rtp_with_attributes(
+        &self,
+        packet: &Packet,
+        attributes: &Attributes,
+    ) -> webrtc::error::Result<usize> {
+        let packet = {
+            let mut forwarding = self.forwarding.lock().unwrap();
+            let keyframe =
+                forwarding.resuming && codec::is_keyframe(&self.mime_type, &packet.payload);
+            let svc = self.svc[forwarding.layer].read().unwrap().parse(packet);
+            match forwarding.forward(packet, keyframe, svc) {
+                Some(packet) => packet,
+                None => return Ok(0),
+            }
+        };
+        self.local
+            .write_rtp_with_attributes(&packet, attributes)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(sequence_number: u16, timestamp: u32) -> Arc<Packet> {
+        Arc::new(Packet {
+            header: Header {
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn cached(gop: &GopCache) -> Vec<u16> {
+        gop.packets
+            .iter()
+            .map(|packet| packet.header.sequence_number)
+            .collect()
+    }
+
+    #[test]
+    fn nothing_is_cached_before_a_keyframe() {
+        let mut gop = GopCache::default();
+        gop.push(&packet(1, 0), false);
+        gop.push(&packet(2, 3000), false);
+        assert!(gop.packets.is_empty());
+
+        gop.push(&packet(3, 6000), true);
+        gop.push(&packet(4, 9000), false);
+        assert_eq!(cached(&gop), vec![3, 4]);
+    }
+
+    #[test]
+    fn keyframe_starts_a_new_group() {
+        let mut gop = GopCache::default();
+        gop.push(&packet(1, 0), true);
+        gop.push(&packet(2, 3000), false);
+        gop.push(&packet(3, 6000), true);
+        assert_eq!(cached(&gop), vec![3]);
+    }
+
+    #[test]
+    fn parameter_sets_stay_with_their_keyframe() {
+        let mut gop = GopCache::default();
+        // SPS, PPS and the IDR slice, all taken for keyframes, share a timestamp
+        gop.push(&packet(1, 3000), true);
+        gop.push(&packet(2, 3000), true);
+        gop.push(&packet(3, 3000), true);
+        gop.push(&packet(4, 6000), false);
+        assert_eq!(cached(&gop), vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn caching_stops_past_the_limit_until_the_next_keyframe() {
+        let mut gop = GopCache::default();
+        gop.push(&packet(0, 0), true);
+        for sequence_number in 1..MAX_GOP_PACKETS as u16 {
+            gop.push(&packet(sequence_number, 3000), false);
+        }
+        assert_eq!(gop.packets.len(), MAX_GOP_PACKETS);
+
+        gop.push(&packet(MAX_GOP_PACKETS as u16, 6000), false);
+        assert!(gop.packets.is_empty());
+        gop.push(&packet(MAX_GOP_PACKETS as u16 + 1, 9000), false);
+        assert!(gop.packets.is_empty());
+
+        gop.push(&packet(MAX_GOP_PACKETS as u16 + 2, 12000), true);
+        assert_eq!(cached(&gop), vec![MAX_GOP_PACKETS as u16 + 2]);
+    }
+}
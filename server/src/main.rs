@@ -1,4 +1,29 @@
-use std::{collections::HashMap, env, sync::Arc, time::Duration};
+mod auth;
+mod bits;
+mod bwe;
+mod codec;
+mod config;
+mod fanout;
+mod ice;
+mod layer;
+mod mux;
+mod network;
+mod retransmit;
+mod rewrite;
+mod sdpfrag;
+mod session;
+mod slate;
+mod stream;
+mod svc;
+mod tcp;
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
 use argh::{FromArgs, from_env};
 
@@ -6,44 +31,84 @@ use actix_cors::Cors;
 use actix_files as fs;
 use actix_web::{
     App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder, ResponseError, delete,
-    http::{StatusCode, header::ContentType},
+    error::{JsonPayloadError, QueryPayloadError},
+    get,
+    http::{StatusCode, header},
     middleware, options, patch, post,
-    web::{self, Data, Path},
+    web::{self, Data, Path, Query},
 };
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 
-use tokio::{net::UdpSocket, sync::Mutex};
+use tokio::{net::UdpSocket, sync::mpsc};
 use webrtc::{
     api::{
         API, APIBuilder,
-        interceptor_registry::register_default_interceptors,
-        media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS, MediaEngine},
+        interceptor_registry::{configure_rtcp_reports, configure_twcc},
+        media_engine::MediaEngine,
         setting_engine::SettingEngine,
     },
     ice::{
-        udp_mux::{UDPMuxDefault, UDPMuxParams},
+        udp_mux::{UDPMux, UDPMuxDefault, UDPMuxParams},
         udp_network::UDPNetwork,
     },
-    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
-    interceptor::registry::Registry,
+    ice_transport::{
+        ice_candidate_type::RTCIceCandidateType, ice_gathering_state::RTCIceGatheringState,
+    },
+    interceptor::{nack::generator::Generator, registry::Registry},
     peer_connection::{
         RTCPeerConnection, configuration::RTCConfiguration,
+        peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription,
     },
-    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
-    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType},
+    rtp_transceiver::{
+        RTCPFeedback, RTCRtpTransceiver,
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpHeaderExtensionCapability, RTPCodecType},
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+    },
     track::{
-        track_local::{TrackLocal, TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
+        track_local::{TrackLocal, track_local_static_rtp::TrackLocalStaticRTP},
         track_remote::TrackRemote,
     },
 };
 
 use uuid::Uuid;
+use webrtc::sdp::{
+    SessionDescription,
+    extmap::{SDES_MID_URI, SDES_REPAIR_RTP_STREAM_ID_URI, SDES_RTP_STREAM_ID_URI},
+};
+
+use config::{Config, ConfigError, non_empty_env, override_with};
+use ice::IceServers;
+use mux::MultiUdpMux;
+use network::{IpNetwork, NatCandidateType, NatMapping, PortRange};
+use retransmit::RtxStreams;
+use sdpfrag::TrickleFragment;
+use session::{Session, SessionKind, SessionRegistry};
+use slate::Slate;
+use stream::{LayerError, OfferedTrack, PublisherPolicy, StreamRegistry, ViewerTrack};
+use tcp::{IceTcpCandidates, IceTcpListener};
+
+use auth::{
+    Action, AllowAll, AuthError, HmacTokens, StaticKeys, StreamAuthorizer, ViewerPolicies, Webhook,
+};
 
 /// Whip signaling broadcast server
 #[derive(FromArgs)]
 struct Args {
-    /// an optional port to setup the web server
+    /// an optional TOML config file, overridden by the environment and flags
+    #[argh(option, short = 'c')]
+    config: Option<PathBuf>,
+
+    /// print the effective configuration and exit
+    #[argh(switch)]
+    print_config: bool,
+
+    /// an address to bind the web server to, can be repeated, e.g. 0.0.0.0
+    /// and :: for both IPv4 and IPv6 (default: 0.0.0.0)
+    #[argh(option)]
+    bind_address: Vec<IpAddr>,
+
+    /// an optional port to setup the web server (default: 8080)
     #[argh(option, short = 'p')]
     port: Option<u16>,
 
@@ -51,20 +116,386 @@ struct Args {
     #[argh(option, short = 'u')]
     udp_mux_port: Option<u16>,
 
-    /// an optional list of ips separated by '|' to setup nat 1 to 1
+    /// an address to bind the udp mux and ICE-TCP to, can be repeated
+    /// (default: 0.0.0.0)
+    #[argh(option)]
+    udp_mux_address: Vec<IpAddr>,
+
+    /// an optional port to accept ICE over TCP on, for clients whose UDP is
+    /// blocked, which needs udp muxing
+    #[argh(option, short = 't')]
+    ice_tcp_port: Option<u16>,
+
+    /// an optional list of public ips separated by ',' to setup nat 1 to 1,
+    /// each for all local ips or written public/local
     #[argh(option, short = 'i')]
     nat_ips: Option<String>,
+
+    /// how the nat ips are advertised: host (default) or srflx
+    #[argh(option)]
+    nat_candidate_type: Option<NatCandidateType>,
+
+    /// an interface candidates are gathered on, can be repeated (default: all)
+    #[argh(option)]
+    interface: Vec<String>,
+
+    /// an interface candidates are not gathered on, can be repeated
+    #[argh(option)]
+    deny_interface: Vec<String>,
+
+    /// an ip or network candidates are gathered on, can be repeated
+    /// (default: all)
+    #[argh(option)]
+    allow_ip: Vec<IpNetwork>,
+
+    /// an ip or network candidates are not gathered on, can be repeated
+    #[argh(option)]
+    deny_ip: Vec<IpNetwork>,
+
+    /// whether IPv4 candidates are gathered: true (default) or false
+    #[argh(option)]
+    ipv4: Option<bool>,
+
+    /// whether IPv6 candidates are gathered: true (default) or false
+    #[argh(option)]
+    ipv6: Option<bool>,
+
+    /// an optional range of ports like 10000-20000 for the per connection
+    /// udp sockets, unused with udp muxing
+    #[argh(option)]
+    udp_port_range: Option<PortRange>,
+
+    /// an optional directory of the web client (default: ./static)
+    #[argh(option)]
+    static_dir: Option<PathBuf>,
+
+    /// an origin allowed to call the API, can be repeated (default: any)
+    #[argh(option)]
+    cors_origin: Vec<String>,
+
+    /// an optional TOML or JSON file mapping stream names to their secret
+    #[argh(option)]
+    auth_keys: Option<PathBuf>,
+
+    /// an optional secret used to verify HMAC signed expiring tokens
+    #[argh(option)]
+    auth_hmac_secret: Option<String>,
+
+    /// an optional url called to authorize tokens
+    #[argh(option)]
+    auth_webhook: Option<String>,
+
+    /// whether the last group of pictures is replayed to new viewers for an
+    /// instant start: true or false (default)
+    #[argh(option)]
+    gop_cache: Option<bool>,
+
+    /// what a second publisher of a live stream gets: reject (default) with
+    /// 409 Conflict, or takeover of the stream from the current publisher
+    #[argh(option)]
+    publisher_policy: Option<PublisherPolicy>,
+
+    /// how many seconds a disconnected session is kept before being torn
+    /// down (default: 30)
+    #[argh(option)]
+    reap_grace: Option<u64>,
+
+    /// how many seconds viewers wait for a publisher that left to come back,
+    /// carrying on where it stopped, before being closed (default: 30)
+    #[argh(option)]
+    publisher_grace: Option<u64>,
+
+    /// whether to answer as soon as the host candidates are gathered, the
+    /// others being fetched with PATCH: true or false (default)
+    #[argh(option)]
+    trickle_answer: Option<bool>,
+
+    /// how many seconds ICE gathering may hold an answer back (default: 5)
+    #[argh(option)]
+    gathering_timeout: Option<u64>,
+
+    /// an optional TOML or JSON file with per stream viewer policies
+    #[argh(option)]
+    viewer_policies: Option<PathBuf>,
+
+    /// an optional H264 Annex B keyframe shown to viewers waiting for a
+    /// publisher
+    #[argh(option)]
+    slate: Option<PathBuf>,
+
+    /// an optional TOML or JSON file listing the STUN and TURN servers
+    #[argh(option)]
+    ice_servers: Option<PathBuf>,
+
+    /// print an HMAC signed token for this stream and exit
+    #[argh(option)]
+    mint_token: Option<String>,
+
+    /// the action granted by the minted token: publish (default) or play
+    #[argh(option, default = "Action::Publish")]
+    mint_action: Action,
+
+    /// how many seconds the minted token is valid (default: 86400)
+    #[argh(option, default = "86400")]
+    mint_ttl: u64,
+}
+
+impl Args {
+    /// Layers the configuration: defaults, config file, environment as read
+    /// by `env`, then flags
+    fn config(
+        &self,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> std::result::Result<Config, ConfigError> {
+        let path = self
+            .config
+            .clone()
+            .or(env("OMNIROOM_CONFIG").map(PathBuf::from));
+        let mut config = Config::load(path.as_deref())?;
+        config.merge_env(env)?;
+
+        if !self.bind_address.is_empty() {
+            config.bind_addresses = self.bind_address.clone();
+        }
+        override_with(&mut config.port, self.port);
+        override_with(&mut config.udp_mux_port, self.udp_mux_port.map(Some));
+        if !self.udp_mux_address.is_empty() {
+            config.udp_mux_addresses = self.udp_mux_address.clone();
+        }
+        override_with(&mut config.ice_tcp_port, self.ice_tcp_port.map(Some));
+        override_with(&mut config.static_dir, self.static_dir.clone());
+        if !self.cors_origin.is_empty() {
+            config.cors_origins = self.cors_origin.clone();
+        }
+        override_with(&mut config.auth_keys, self.auth_keys.clone().map(Some));
+        override_with(
+            &mut config.auth_hmac_secret,
+            self.auth_hmac_secret.clone().map(Some),
+        );
+        override_with(
+            &mut config.auth_webhook,
+            self.auth_webhook.clone().map(Some),
+        );
+        override_with(
+            &mut config.viewer_policies,
+            self.viewer_policies.clone().map(Some),
+        );
+        override_with(&mut config.slate, self.slate.clone().map(Some));
+        if let Some(path) = &self.ice_servers {
+            config.ice_servers = config::load_ice_servers(path)?;
+        }
+        override_with(&mut config.gop_cache, self.gop_cache);
+        override_with(&mut config.publisher_policy, self.publisher_policy);
+        override_with(&mut config.reap_grace, self.reap_grace);
+        override_with(&mut config.publisher_grace, self.publisher_grace);
+        override_with(&mut config.trickle_answer, self.trickle_answer);
+        override_with(&mut config.gathering_timeout, self.gathering_timeout);
+
+        let network = &mut config.network;
+        if let Some(nat_ips) = &self.nat_ips {
+            network.nat_ips = NatMapping::parse_list(nat_ips)
+                .map_err(|err| ConfigError::Invalid(format!("NAT ip {err}")))?;
+        }
+        override_with(&mut network.nat_candidate_type, self.nat_candidate_type);
+        if !self.interface.is_empty() {
+            network.interfaces = self.interface.clone();
+        }
+        if !self.deny_interface.is_empty() {
+            network.deny_interfaces = self.deny_interface.clone();
+        }
+        if !self.allow_ip.is_empty() {
+            network.allow_ips = self.allow_ip.clone();
+        }
+        if !self.deny_ip.is_empty() {
+            network.deny_ips = self.deny_ip.clone();
+        }
+        override_with(&mut network.ipv4, self.ipv4);
+        override_with(&mut network.ipv6, self.ipv6);
+        override_with(&mut network.udp_port_range, self.udp_port_range.map(Some));
+        Ok(config)
+    }
 }
 
 #[derive(Clone)]
 struct WhipData {
     api: Arc<API>,
     default_config: RTCConfiguration,
-    whips: Arc<Mutex<HashMap<Uuid, Arc<RTCPeerConnection>>>>,
-    subscriptions:
-        Arc<Mutex<HashMap<String, Vec<(Arc<TrackLocalStaticRTP>, Arc<TrackLocalStaticRTP>)>>>>,
+    authorizer: Arc<dyn StreamAuthorizer>,
+    viewer_policies: Arc<ViewerPolicies>,
+    publisher_policy: PublisherPolicy,
+    publisher_policies: Arc<HashMap<String, PublisherPolicy>>,
+    ice_servers: Arc<IceServers>,
+    sessions: SessionRegistry,
+    streams: StreamRegistry,
+    rtx_streams: RtxStreams,
+    trickle_answer: bool,
+    gathering_timeout: Duration,
+    ice_tcp_candidates: IceTcpCandidates,
+}
+
+impl WhipData {
+    /// Answers the remote offer once ICE gathering completes, or with
+    /// `--trickle-answer` once host candidates are found. An unreachable STUN
+    /// server never holds the answer longer than the gathering timeout
+    async fn answer(&self, pc: &RTCPeerConnection) -> Result<()> {
+        let answer = pc.create_answer(None).await?;
+        let mut gathered = pc.gathering_complete_promise().await;
+        let (host_tx, mut host) = mpsc::channel(1);
+        if self.trickle_answer {
+            pc.on_ice_candidate(Box::new(move |candidate| {
+                if candidate.is_some_and(|candidate| candidate.typ == RTCIceCandidateType::Host) {
+                    let _ = host_tx.try_send(());
+                }
+                Box::pin(async {})
+            }));
+        }
+        pc.set_local_description(answer).await?;
+
+        let ready = async {
+            tokio::select! {
+                _ = gathered.recv() => {}
+                Some(()) = host.recv() => {}
+            }
+        };
+        if tokio::time::timeout(self.gathering_timeout, ready)
+            .await
+            .is_err()
+        {
+            eprintln!("ICE gathering timed out, answering with the candidates found so far");
+        }
+        Ok(())
+    }
+
+    /// Local description of `pc` as sent to clients, with the ICE-TCP candidates
+    async fn local_description(&self, pc: &RTCPeerConnection) -> Result<String> {
+        let sdp = pc
+            .local_description()
+            .await
+            .ok_or(Error::MissingDescription("local"))?
+            .sdp;
+        Ok(self.ice_tcp_candidates.add_to(&sdp))
+    }
+
+    fn publisher_policy(&self, stream_key: &str) -> PublisherPolicy {
+        self.publisher_policies
+            .get(stream_key)
+            .copied()
+            .unwrap_or(self.publisher_policy)
+    }
+
+    /// Registers a session and forgets it once its peer connection fails or closes
+    ///
+    /// The session is ended from its own task: closing the peer connection
+    /// runs the state change handler again, which waits for this one to return
+    async fn register_session(&self, session_id: Uuid, session: Session) {
+        let pc = session.pc.clone();
+        self.sessions.insert(session_id, session).await;
+
+        let whip_data = self.clone();
+        pc.on_peer_connection_state_change(Box::new(move |state| {
+            let whip_data = whip_data.clone();
+            Box::pin(async move {
+                match state {
+                    RTCPeerConnectionState::Connected => {
+                        if let Err(err) = whip_data.session_connected(&session_id).await {
+                            eprintln!("Failed to start session {session_id}: {err}");
+                        }
+                    }
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed => {
+                        tokio::spawn(async move {
+                            match whip_data.end_session(&session_id).await {
+                                // Already ended by a DELETE
+                                Ok(_) | Err(Error::SessionNotFound(_)) => {}
+                                Err(err) => eprintln!("Failed to end session {session_id}: {err}"),
+                            }
+                        });
+                    }
+                    _ => {}
+                }
+            })
+        }));
+    }
+
+    /// Starts forwarding media to a viewer once its connection is up
+    async fn session_connected(&self, session_id: &Uuid) -> Result<()> {
+        let Some(session) = self.sessions.get(session_id).await else {
+            return Ok(());
+        };
+        if session.kind == SessionKind::Viewer
+            && let Some(stream) = self.streams.get(&session.stream_key).await
+        {
+            stream.start_viewer(session_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes a session from the registry, detaches its tracks and closes it
+    async fn end_session(&self, session_id: &Uuid) -> Result<Arc<Session>> {
+        let session = self
+            .sessions
+            .remove(session_id)
+            .await
+            .ok_or(Error::SessionNotFound(*session_id))?;
+
+        self.streams
+            .remove_session(&session.stream_key, session_id)
+            .await;
+
+        println!(
+            "Ending {:?} session {session_id} on {} after {}s",
+            session.kind,
+            session.stream_key,
+            session.created_at.elapsed().unwrap_or_default().as_secs()
+        );
+        session.pc.close().await?;
+        Ok(session)
+    }
+
+    /// Periodically tears down sessions that stayed disconnected longer than `grace`,
+    /// such as a browser tab closed without sending a DELETE, and the viewers
+    /// of publishers gone for longer than `publisher_grace`
+    fn spawn_reaper(&self, grace: Duration, publisher_grace: Duration) {
+        let whip_data = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAPER_INTERVAL);
+            loop {
+                interval.tick().await;
+                whip_data.reap(grace, publisher_grace).await;
+            }
+        });
+    }
+
+    /// Ends the sessions unhealthy for longer than `grace`, and the viewers
+    /// whose publisher did not come back within `publisher_grace`
+    async fn reap(&self, grace: Duration, publisher_grace: Duration) {
+        for (session_id, session) in self.sessions.list().await {
+            if session
+                .unhealthy_for()
+                .is_some_and(|elapsed| elapsed > grace)
+            {
+                println!("Reaping session {session_id}");
+                match self.end_session(&session_id).await {
+                    Ok(_) | Err(Error::SessionNotFound(_)) => {}
+                    Err(err) => eprintln!("Failed to reap session {session_id}: {err}"),
+                }
+            }
+        }
+        for session_id in self.streams.abandoned_viewers(publisher_grace).await {
+            println!("Publisher did not come back, ending viewer {session_id}");
+            match self.end_session(&session_id).await {
+                Ok(_) | Err(Error::SessionNotFound(_)) => {}
+                Err(err) => eprintln!("Failed to end viewer {session_id}: {err}"),
+            }
+        }
+    }
 }
 
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Bounds of the metadata a publisher gives in the query string
+const MAX_METADATA_ENTRIES: usize = 16;
+const MAX_METADATA_LENGTH: usize = 1024;
+
 type Result<T> = std::result::Result<T, Error>;
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -76,29 +507,103 @@ pub enum Error {
     #[error("Bad UUID: {0}")]
     BadUuid(#[from] uuid::Error),
 
+    #[error("Session not found: {0}")]
+    SessionNotFound(Uuid),
+
+    #[error("Stream not found: {0}")]
+    StreamNotFound(String),
+
+    #[error("Invalid metadata: {0}")]
+    InvalidMetadata(String),
+
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+
+    #[error("No such resource: {0}")]
+    RouteNotFound(String),
+
+    #[error("Stream {0} already has a publisher")]
+    StreamAlreadyPublishing(String),
+
+    #[error("Session {0} is not a viewer")]
+    NotAViewer(Uuid),
+
+    #[error("Expected an {0} body")]
+    UnsupportedMediaType(&'static str),
+
+    #[error("Malformed SDP: {0}")]
+    MalformedSdp(String),
+
+    #[error("Session has no {0} description")]
+    MissingDescription(&'static str),
+
     #[error("Webrtc Error: {0}")]
     WebrtcError(#[from] webrtc::Error),
 
+    #[error("{0}")]
+    AuthError(#[from] AuthError),
+
+    #[error("Viewer cannot receive the published {0}")]
+    UnsupportedCodec(String),
+
+    #[error("{0}")]
+    LayerError(#[from] LayerError),
+
+    #[error("ICE session changed, expected {0}")]
+    IceSessionMismatch(String),
+
     #[error("Internal Error: {0}")]
     InternalError(String),
 }
 
 impl ResponseError for Error {
     fn status_code(&self) -> actix_web::http::StatusCode {
-        eprintln!("{}", self);
         match self {
             Error::SessionGetError(_) => StatusCode::SERVICE_UNAVAILABLE,
             Error::SessionInsertError(_) => StatusCode::SERVICE_UNAVAILABLE,
             Error::BadUuid(_) => StatusCode::BAD_REQUEST,
+            Error::SessionNotFound(_) => StatusCode::NOT_FOUND,
+            Error::StreamNotFound(_) => StatusCode::NOT_FOUND,
+            Error::InvalidMetadata(_) => StatusCode::BAD_REQUEST,
+            Error::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            Error::RouteNotFound(_) => StatusCode::NOT_FOUND,
+            Error::StreamAlreadyPublishing(_) => StatusCode::CONFLICT,
+            Error::NotAViewer(_) => StatusCode::CONFLICT,
+            Error::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::MalformedSdp(_) => StatusCode::BAD_REQUEST,
+            Error::MissingDescription(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Error::WebrtcError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::AuthError(AuthError::MissingToken(_)) => StatusCode::UNAUTHORIZED,
+            Error::AuthError(AuthError::Unauthorized(_)) => StatusCode::UNAUTHORIZED,
+            Error::AuthError(AuthError::Forbidden(_)) => StatusCode::FORBIDDEN,
+            Error::AuthError(AuthError::Unavailable(_)) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::UnsupportedCodec(_) => StatusCode::NOT_ACCEPTABLE,
+            Error::LayerError(_) => StatusCode::BAD_REQUEST,
+            Error::IceSessionMismatch(_) => StatusCode::PRECONDITION_FAILED,
             Error::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
+    /// A problem details body (RFC 7807)
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::html())
-            .body(self.to_string())
+        eprintln!("{}", self);
+        let status = self.status_code();
+        let mut res = HttpResponse::build(status);
+        res.content_type("application/problem+json");
+        if let Error::AuthError(err) = self
+            && let Some(challenge) = err.www_authenticate()
+        {
+            res.insert_header((header::WWW_AUTHENTICATE, challenge));
+        }
+        res.body(
+            serde_json::json!({
+                "type": "about:blank",
+                "title": status.canonical_reason(),
+                "status": status.as_u16(),
+                "detail": self.to_string(),
+            })
+            .to_string(),
+        )
     }
 }
 
@@ -107,11 +612,9 @@ async fn whip_options(whip_data: Data<WhipData>) -> Result<impl Responder> {
     let mut res = HttpResponse::Ok();
     res.content_type("application/sdp");
 
-    // Headers
-    for ice_server in whip_data.default_config.ice_servers.iter() {
-        for url in ice_server.urls.iter() {
-            res.insert_header(("Link", format!("<{url}>; rel=\"ice-server\";")));
-        }
+    // Headers, TURN credentials are only given to authorized sessions
+    for link in whip_data.ice_servers.stun_links() {
+        res.append_header(("Link", link));
     }
 
     Ok(res)
@@ -119,13 +622,40 @@ async fn whip_options(whip_data: Data<WhipData>) -> Result<impl Responder> {
 
 #[post("/whip")]
 async fn whip(
-    auth: BearerAuth,
+    req: HttpRequest,
+    auth: Option<BearerAuth>,
+    metadata: Query<HashMap<String, String>>,
     offer: String,
     whip_data: Data<WhipData>,
 ) -> Result<impl Responder> {
+    expect_content_type(&req, "application/sdp")?;
+    // Taken as an option to answer a missing token like any other refusal
+    let token = auth
+        .as_ref()
+        .map(|auth| auth.token())
+        .ok_or_else(|| AuthError::MissingToken("missing bearer token".to_string()))?;
+    let metadata = metadata.into_inner();
+    if metadata.len() > MAX_METADATA_ENTRIES
+        || metadata
+            .iter()
+            .any(|(key, value)| key.len() + value.len() > MAX_METADATA_LENGTH)
+    {
+        return Err(Error::InvalidMetadata(format!(
+            "at most {MAX_METADATA_ENTRIES} entries of {MAX_METADATA_LENGTH} bytes"
+        )));
+    }
+    let stream_key = whip_data
+        .authorizer
+        .authorize(Action::Publish, token)
+        .await?;
+    let policy = whip_data.publisher_policy(&stream_key);
+    if let Some(stream) = whip_data.streams.get(&stream_key).await
+        && !stream.accepts_publisher(policy)
+    {
+        return Err(Error::StreamAlreadyPublishing(stream_key));
+    }
     let session_id = Uuid::new_v4();
     println!("New whip session: {session_id}");
-    let stream_key = auth.token().to_string();
     let pc = Arc::new(
         whip_data
             .api
@@ -135,77 +665,136 @@ async fn whip(
 
     let wd = whip_data.clone();
     let sk = stream_key.clone();
-    let pc2 = pc.clone();
-    pc.on_track(Box::new(move |track: Arc<TrackRemote>, _, _| {
-        // RTCP
-        let media_ssrc = track.ssrc();
-        let pc2 = pc2.clone();
-        tokio::spawn(async move {
-            let pc2 = pc2.clone();
-            let mut result = webrtc::error::Result::<usize>::Ok(0);
-            while result.is_ok() {
-                let pc2 = pc2.clone();
-                let timeout = tokio::time::sleep(Duration::from_secs(3));
-                tokio::pin!(timeout);
-
-                tokio::select! {
-                _ = timeout.as_mut() =>{
-                        result = pc2.write_rtcp(&[Box::new(PictureLossIndication{
-                            sender_ssrc: 0,
-                            media_ssrc,
-                        })]).await.map_err(Into::into);
-                    }
+    pc.on_track(Box::new(
+        move |track: Arc<TrackRemote>, _, transceiver: Arc<RTCRtpTransceiver>| {
+            let streams = wd.streams.clone();
+            let sk = sk.clone();
+            tokio::spawn(async move {
+                let Some(stream) = streams.get(&sk).await else {
+                    return;
+                };
+                let codec = track.codec().capability;
+                let dependency_descriptor_id = track
+                    .params()
+                    .header_extensions
+                    .iter()
+                    .find(|extension| extension.uri == svc::DEPENDENCY_DESCRIPTOR_URI)
+                    .map(|extension| extension.id as u8);
+                let Some(published) = transceiver.mid().and_then(|mid| {
+                    stream.publisher_track(
+                        &session_id,
+                        &mid,
+                        track.rid(),
+                        track.ssrc(),
+                        codec.clone(),
+                        dependency_descriptor_id,
+                    )
+                }) else {
+                    return;
+                };
+                let Some(layer) = published.layer(track.rid()) else {
+                    return;
+                };
+                // Viewers left from a previous publisher decode from a keyframe of this one
+                if published.kind == RTPCodecType::Video
+                    && let Err(err) = stream.request_keyframe().await
+                {
+                    eprintln!("Failed to ask the new publisher for a keyframe: {err}");
                 }
-            }
-        });
-
-        let subscribers_lock = wd.subscriptions.clone();
-        let sk = sk.clone();
-        tokio::spawn(async move {
-            while let Ok((rtp, _)) = track.read_rtp().await {
-                let sk = sk.clone();
-                let mut subscriptions = subscribers_lock.lock().await;
-                let subscribers = subscriptions.get(&sk);
-                if let Some(subscribers) = subscribers {
-                    for (video_track, audio_track) in subscribers {
-                        match track.kind() {
-                            RTPCodecType::Video => {
-                                video_track.write_rtp(&rtp).await.unwrap();
-                            }
-                            RTPCodecType::Audio => {
-                                audio_track.write_rtp(&rtp).await.unwrap();
-                            }
-                            RTPCodecType::Unspecified => {}
-                        };
+                while let Ok((rtp, _)) = track.read_rtp().await {
+                    let keyframe = codec::is_keyframe(&codec.mime_type, &rtp.payload);
+                    if keyframe
+                        && let Some(resolution) = codec::resolution(&codec.mime_type, &rtp.payload)
+                    {
+                        layer.set_resolution(resolution);
                     }
-                } else {
-                    subscriptions.insert(sk, Vec::new());
+                    layer.publish(rtp, keyframe);
                 }
-            }
-        });
-        Box::pin(async move {})
-    }));
-
-    pc.set_remote_description(RTCSessionDescription::offer(offer)?)
-        .await?;
-    let answer = pc.create_answer(None).await?;
-    pc.set_local_description(answer.clone()).await?;
-
-    pc.gathering_complete_promise().await.recv().await;
+            });
+            Box::pin(async move {})
+        },
+    ));
 
-    whip_data.whips.lock().await.insert(session_id, pc.clone());
+    let negotiation = async {
+        pc.set_remote_description(parse_offer(offer)?).await?;
+        whip_data.answer(&pc).await?;
+        Ok(pc
+            .remote_description()
+            .await
+            .ok_or(Error::MissingDescription("remote"))?
+            .unmarshal()?)
+    };
+    // Tracks are known from the offer, before their first packet
+    let remote_description = close_on_error(&pc, negotiation.await).await?;
+    let mut offered = Vec::new();
+    for transceiver in pc.get_transceivers().await {
+        let receiving = matches!(
+            transceiver.direction(),
+            RTCRtpTransceiverDirection::Recvonly | RTCRtpTransceiverDirection::Sendrecv
+        );
+        let Some(mid) = transceiver.mid().filter(|_| receiving) else {
+            continue;
+        };
+        let parameters = transceiver.receiver().await.get_parameters().await;
+        if let Some(codec) = parameters.codecs.into_iter().next() {
+            offered.push(OfferedTrack {
+                rids: offered_rids(&remote_description, &mid),
+                mid: mid.to_string(),
+                kind: transceiver.kind(),
+                codec: codec.capability,
+            });
+        }
+    }
+    // Another publisher may have come in during the negotiation
+    let displaced = whip_data
+        .streams
+        .get_or_create(&stream_key)
+        .await
+        .set_publisher(session_id, pc.clone(), offered, metadata, policy)
+        .map_err(|_| Error::StreamAlreadyPublishing(stream_key.clone()));
+    let displaced = close_on_error(&pc, displaced).await?;
+    whip_data
+        .register_session(
+            session_id,
+            Session::new(
+                SessionKind::Publisher,
+                stream_key,
+                pc.clone(),
+                Some(token.to_string()),
+            ),
+        )
+        .await;
+
+    if let Some(replaced) = displaced.publisher {
+        println!("Publisher {session_id} takes over from {replaced}");
+        match whip_data.end_session(&replaced).await {
+            Ok(_) | Err(Error::SessionNotFound(_)) => {}
+            Err(err) => eprintln!("Failed to end replaced publisher {replaced}: {err}"),
+        }
+    }
+    // They would need to negotiate the new codec
+    for viewer in displaced.viewers {
+        println!("Publisher {session_id} sends a codec viewer {viewer} cannot decode");
+        match whip_data.end_session(&viewer).await {
+            Ok(_) | Err(Error::SessionNotFound(_)) => {}
+            Err(err) => eprintln!("Failed to end viewer {viewer}: {err}"),
+        }
+    }
 
-    let late_answer = pc.local_description().await.unwrap().sdp;
+    let late_answer = whip_data.local_description(&pc).await?;
+    if let Some(session) = whip_data.sessions.get(&session_id).await {
+        session.deliver_candidates(sdpfrag::local_candidates(&late_answer), false);
+    }
 
     let mut res = HttpResponse::Created();
     res.content_type("application/sdp");
 
     // Headers
     res.insert_header(("Location", format!("/api/resource/{session_id}")));
-    for ice_server in whip_data.default_config.ice_servers.iter() {
-        for url in ice_server.urls.iter() {
-            res.insert_header(("Link", format!("<{url}>; rel=\"ice-server\";")));
-        }
+    res.insert_header((header::ETAG, ice_etag(&late_answer)));
+    res.insert_header(("Accept-Patch", "application/trickle-ice-sdpfrag"));
+    for link in whip_data.ice_servers.links(&session_id.to_string()) {
+        res.append_header(("Link", link));
     }
 
     Ok(res.body(late_answer))
@@ -213,131 +802,192 @@ async fn whip(
 
 #[delete("/resource/{session_id}")]
 async fn whip_delete(
-    auth: BearerAuth,
+    auth: Option<BearerAuth>,
     session_id: Path<String>,
     whip_data: Data<WhipData>,
 ) -> Result<impl Responder> {
     let session_id = Uuid::parse_str(&session_id)?;
-    let stream_key = auth.token().to_string();
-    let whips = whip_data.whips.lock().await;
-    let pc = whips.get(&session_id).unwrap();
-    pc.close().await?;
+    let session = whip_data
+        .sessions
+        .get(&session_id)
+        .await
+        .ok_or(Error::SessionNotFound(session_id))?;
 
-    let mut subscriptions = whip_data.subscriptions.lock().await;
-    subscriptions.remove(&stream_key);
+    authorize_session(&session, auth, "deleting")?;
+
+    whip_data.end_session(&session_id).await?;
     Ok(HttpResponse::Ok())
 }
 
-enum ExpectedFields {
-    NONE,
-    N(usize),
-    MANY,
+/// Checks the caller may change `session`, with the token it was created
+/// with: a publisher can end its session after its token expired, and the
+/// authorizer is not asked again
+fn authorize_session(session: &Session, auth: Option<BearerAuth>, action: &str) -> Result<()> {
+    let Some(expected) = &session.token else {
+        return Ok(());
+    };
+    match auth.as_ref().map(|auth| auth.token()) {
+        None => Err(AuthError::MissingToken(format!(
+            "{action} a session needs the token it was created with"
+        ))
+        .into()),
+        Some(token) if !auth::constant_time_eq(expected.as_bytes(), token.as_bytes()) => {
+            Err(AuthError::Forbidden(format!(
+                "{action} a session needs the token it was created with"
+            ))
+            .into())
+        }
+        Some(_) => Ok(()),
+    }
 }
 
-fn extract_sdp_field<'a>(
-    lines: Vec<&'a str>,
-    prefix: &'a str,
-    expects: ExpectedFields,
-) -> Result<Vec<&'a str>> {
-    let fields: Vec<&str> = lines
-        .into_iter()
-        .filter_map(|line| line.strip_prefix(prefix))
+/// Simulcast layers sent on `mid` (RFC 8853), a single unnamed one without simulcast
+fn offered_rids(offer: &SessionDescription, mid: &str) -> Vec<String> {
+    let rids: Vec<String> = offer
+        .media_descriptions
+        .iter()
+        .filter(|media| media.attribute("mid") == Some(Some(mid)))
+        .flat_map(|media| &media.attributes)
+        .filter(|attribute| attribute.key == "rid")
+        .filter_map(|attribute| attribute.value.as_deref()?.split_once(' '))
+        .filter(|(_, direction)| direction.starts_with("send"))
+        .map(|(rid, _)| rid.to_string())
         .collect();
-
-    match expects {
-        ExpectedFields::NONE => {
-            if fields.len() != 0 {
-                return Err(Error::InternalError("SDP malformed".to_string()));
-            }
-        }
-        ExpectedFields::N(n) => {
-            if fields.len() != n {
-                return Err(Error::InternalError("SDP malformed".to_string()));
-            }
-        }
-        ExpectedFields::MANY => {
-            if fields.len() == 0 {
-                return Err(Error::InternalError("SDP malformed".to_string()));
-            }
-        }
+    if rids.is_empty() {
+        vec![String::new()]
+    } else {
+        rids
     }
-    Ok(fields)
 }
 
+/// How many `m=` sections of this kind an SDP has
+fn count_media_sections(sdp: &str, kind: RTPCodecType) -> usize {
+    let prefix = format!("m={kind} ");
+    sdp.lines().filter(|line| line.starts_with(&prefix)).count()
+}
+
+/// Entity tag of a session's ICE session, from the local ICE ufrag which
+/// changes on restart
+fn ice_etag(local_description: &str) -> String {
+    format!(
+        "\"{}\"",
+        sdpfrag::ice_ufrag(local_description).unwrap_or_default()
+    )
+}
+
+/// Trickles the client's candidates (RFC 9725), answering with the server
+/// candidates it was not sent yet, or restarts ICE when the credentials change
 #[patch("/resource/{session_id}")]
 async fn whip_patch(
     req: HttpRequest,
+    auth: Option<BearerAuth>,
     session_id: Path<String>,
     sdp_patch: String,
     whip_data: Data<WhipData>,
 ) -> Result<impl Responder> {
-    if req.content_type() != "application/trickle-ice-sdpfrag" {
-        return Ok(HttpResponse::BadRequest());
+    let session_id = Uuid::parse_str(&session_id)?;
+    let session = whip_data
+        .sessions
+        .get(&session_id)
+        .await
+        .ok_or(Error::SessionNotFound(session_id))?;
+    // An ICE restart hands the media path to whoever sends it
+    authorize_session(&session, auth, "patching")?;
+
+    expect_content_type(&req, "application/trickle-ice-sdpfrag")?;
+    let fragment = TrickleFragment::parse(&sdp_patch)
+        .ok_or_else(|| Error::MalformedSdp("the fragment has no ICE ufrag and pwd".to_string()))?;
+    let pc = &session.pc;
+
+    let local_description = whip_data.local_description(pc).await?;
+    let etag = ice_etag(&local_description);
+    let if_match = req
+        .headers()
+        .get(header::IF_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if let Some(if_match) = if_match
+        && !if_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == etag)
+    {
+        return Err(Error::IceSessionMismatch(etag));
     }
 
-    let session_id = Uuid::parse_str(&session_id)?;
-    let patch_lines: Vec<&str> = sdp_patch.split("\r\n").collect();
-
-    let patch_ufrags =
-        extract_sdp_field(patch_lines.clone(), "a=ice-ufrag:", ExpectedFields::N(1))?;
-    let patch_ufrag = patch_ufrags.last().unwrap();
-    let patch_pwds = extract_sdp_field(patch_lines.clone(), "a=ice-pwd:", ExpectedFields::N(1))?;
-    let patch_pwd = patch_pwds.last().unwrap();
-
-    let whips = whip_data.whips.lock().await;
-    let pc = whips.get(&session_id).unwrap();
-
-    let remote_description = pc.remote_description().await.unwrap().sdp;
-    let description_lines: Vec<&str> = remote_description.split("\r\n").collect();
-
-    let current_ufrags = extract_sdp_field(
-        description_lines.clone(),
-        "a=ice-ufrag:",
-        ExpectedFields::MANY,
-    )?;
-    let current_ufrag = current_ufrags.first().unwrap();
-    let current_pwds = extract_sdp_field(
-        description_lines.clone(),
-        "a=ice-pwd:",
-        ExpectedFields::MANY,
-    )?;
-    let current_pwd = current_pwds.first().unwrap();
-
-    if current_ufrag == patch_ufrag && current_pwd == patch_pwd {
-        let candidates: Vec<&str> = patch_lines
-            .clone()
-            .into_iter()
-            .filter(|line| line.starts_with("a=candidate"))
-            .filter_map(|candidate| candidate.strip_prefix("a="))
-            .collect();
+    let remote_description = pc
+        .remote_description()
+        .await
+        .ok_or(Error::MissingDescription("remote"))?
+        .sdp;
+    let restart = sdpfrag::ice_ufrag(&remote_description) != Some(fragment.ufrag.as_str())
+        || !remote_description.contains(&format!("a=ice-pwd:{}", fragment.pwd));
+    if restart {
+        let offer = sdpfrag::with_credentials(&remote_description, &fragment.ufrag, &fragment.pwd);
+        pc.set_remote_description(parse_offer(offer)?).await?;
+    }
+    for candidate in fragment.candidates {
+        pc.add_ice_candidate(candidate).await?;
+    }
 
-        for candidate in candidates {
-            pc.add_ice_candidate(webrtc::ice_transport::ice_candidate::RTCIceCandidateInit {
-                candidate: candidate.to_string(),
-                sdp_mid: None,
-                sdp_mline_index: None,
-                username_fragment: None,
-            })
-            .await?;
-        }
+    let local_description = if restart {
+        whip_data.answer(pc).await?;
+        whip_data.local_description(pc).await?
     } else {
-        pc.restart_ice().await?;
+        local_description
+    };
+
+    let delivered = if restart {
+        Default::default()
+    } else {
+        session.delivered_candidates()
+    };
+    let complete = pc.ice_gathering_state() == RTCIceGatheringState::Complete;
+    let (body, candidates) = sdpfrag::local_fragment(&local_description, &delivered, complete);
+    if !restart && candidates.is_empty() {
+        return Ok(HttpResponse::NoContent()
+            .insert_header((header::ETAG, etag))
+            .finish());
     }
+    session.deliver_candidates(candidates, restart);
 
-    let mut res = HttpResponse::Created();
-    res.content_type("application/trickle-ice-sdpfrag");
-    Ok(HttpResponse::NoContent())
+    Ok(HttpResponse::Ok()
+        .content_type("application/trickle-ice-sdpfrag")
+        .insert_header((header::ETAG, ice_etag(&local_description)))
+        .body(body))
+}
+
+#[derive(serde::Deserialize)]
+struct ViewerQuery {
+    token: Option<String>,
 }
 
-#[post("/whep")]
+#[post("/whep/{stream}")]
 async fn whep(
-    auth: BearerAuth,
+    req: HttpRequest,
+    auth: Option<BearerAuth>,
+    stream: Path<String>,
+    query: Query<ViewerQuery>,
     offer: String,
     whip_data: Data<WhipData>,
 ) -> Result<impl Responder> {
+    expect_content_type(&req, "application/sdp")?;
+    let stream_key = stream.into_inner();
+    // Shared watch links carry the token in the query string
+    let token = auth
+        .as_ref()
+        .map(|auth| auth.token())
+        .or(query.token.as_deref());
+    whip_data
+        .viewer_policies
+        .authorize(whip_data.authorizer.as_ref(), &stream_key, token)
+        .await?;
+    // Viewers get exactly what the publisher sends, one track per offered
+    // media section of the same kind
+    let mut room = [RTPCodecType::Video, RTPCodecType::Audio]
+        .map(|kind| (kind, count_media_sections(&offer, kind)));
+    let offer = parse_offer(offer)?;
     let session_id = Uuid::new_v4();
     println!("New whep session: {session_id}");
-    let stream_key = auth.token().to_string();
     let pc = Arc::new(
         whip_data
             .api
@@ -345,87 +995,317 @@ async fn whep(
             .await?,
     );
 
-    let video_track = Arc::new(TrackLocalStaticRTP::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_H264.to_owned(),
-            ..Default::default()
-        },
-        format!("video_{session_id}"),
-        format!("webrtc-rs_{session_id}"),
-    ));
-    let audio_track = Arc::new(TrackLocalStaticRTP::new(
-        RTCRtpCodecCapability {
-            mime_type: MIME_TYPE_OPUS.to_owned(),
-            ..Default::default()
-        },
-        format!("audio_{session_id}"),
-        format!("webrtc-rs_{session_id}"),
+    // Only authorized viewers with a readable offer get to wait for a stream
+    let stream = whip_data.streams.get_or_create(&stream_key).await;
+    let negotiation = async {
+        let mut tracks = Vec::new();
+        for (index, published) in stream.tracks().into_iter().enumerate() {
+            let Some((kind, free)) = room.iter_mut().find(|(kind, _)| *kind == published.kind)
+            else {
+                continue;
+            };
+            if *free == 0 {
+                println!("Viewer {session_id} has no room for {kind} track {index}");
+                continue;
+            }
+            *free -= 1;
+            let local = Arc::new(TrackLocalStaticRTP::new(
+                published.codec(),
+                format!("{kind}{index}_{session_id}"),
+                format!("webrtc-rs_{session_id}"),
+            ));
+            let rtp_sender = pc
+                .add_track(Arc::clone(&local) as Arc<dyn TrackLocal + Send + Sync>)
+                .await?;
+            tracks.push((published, local, rtp_sender));
+        }
+
+        pc.set_remote_description(offer).await?;
+        for (track, local, rtp_sender) in &tracks {
+            let published = local.codec();
+            let accepted = rtp_sender.get_parameters().await.rtp_parameters.codecs;
+            // Waiting viewers are checked against the codecs they wait for, as
+            // the parameters the publisher uses are not known yet
+            let compatible = |offered: &RTCRtpCodecCapability| {
+                if track.is_placeholder() {
+                    codec::is_same_codec(offered, &published)
+                } else {
+                    codec::is_compatible(offered, &published)
+                }
+            };
+            if !accepted
+                .iter()
+                .any(|offered| compatible(&offered.capability))
+            {
+                return Err(Error::UnsupportedCodec(published.mime_type));
+            }
+        }
+        whip_data.answer(&pc).await?;
+        Ok(tracks)
+    };
+    let tracks = close_on_error(&pc, negotiation.await).await;
+    if tracks.is_err() {
+        whip_data
+            .streams
+            .remove_session(&stream_key, &session_id)
+            .await;
+    }
+    let tracks = tracks?;
+
+    let transceivers = pc.get_transceivers().await;
+    let mut viewer_tracks = Vec::new();
+    for (published, local, rtp_sender) in tracks {
+        let mut mid = String::new();
+        for transceiver in &transceivers {
+            if Arc::ptr_eq(&transceiver.sender().await, &rtp_sender) {
+                mid = transceiver.mid().unwrap_or_default().to_string();
+            }
+        }
+        let parameters = rtp_sender.get_parameters().await;
+        let ssrc = parameters
+            .encodings
+            .first()
+            .map_or(0, |encoding| encoding.ssrc);
+        viewer_tracks.push(ViewerTrack::new(published, local, ssrc, mid));
+
+        let stream = stream.clone();
+        let rtx_streams = whip_data.rtx_streams.clone();
+        tokio::spawn(async move {
+            let mut rtcp_buf = vec![0u8; 1500];
+            while let Ok((packets, _)) = rtp_sender.read(&mut rtcp_buf).await {
+                if let Err(err) = stream
+                    .on_viewer_rtcp(&session_id, &packets, &rtx_streams)
+                    .await
+                {
+                    eprintln!("Failed to handle RTCP from viewer {session_id}: {err}");
+                }
+            }
+        });
+    }
+    stream.add_viewer(session_id, viewer_tracks);
+    stream.show_slate();
+    whip_data
+        .register_session(
+            session_id,
+            Session::new(
+                SessionKind::Viewer,
+                stream_key,
+                pc.clone(),
+                token.map(str::to_string),
+            ),
+        )
+        .await;
+
+    let late_answer = whip_data.local_description(&pc).await?;
+    if let Some(session) = whip_data.sessions.get(&session_id).await {
+        session.deliver_candidates(sdpfrag::local_candidates(&late_answer), false);
+    }
+
+    let mut res = HttpResponse::Created();
+    res.content_type("application/sdp");
+
+    // Headers
+    res.insert_header(("Location", format!("/api/resource/{session_id}")));
+    res.insert_header((header::ETAG, ice_etag(&late_answer)));
+    res.insert_header(("Accept-Patch", "application/trickle-ice-sdpfrag"));
+    res.append_header((
+        "Link",
+        format!("</api/resource/{session_id}/layer>; rel=\"urn:ietf:params:whep:ext:core:layer\""),
     ));
+    for link in whip_data.ice_servers.links(&session_id.to_string()) {
+        res.append_header(("Link", link));
+    }
 
-    let rtp_sender_video = pc
-        .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await?;
+    Ok(res.body(late_answer))
+}
 
-    let rtp_sender_audio = pc
-        .add_track(Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>)
-        .await?;
+/// Body of the WHEP layer extension
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LayerRequest {
+    media_id: Option<String>,
+    encoding_id: Option<String>,
+    spatial_layer_id: Option<u8>,
+    temporal_layer_id: Option<u8>,
+}
 
-    tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender_video.read(&mut rtcp_buf).await {}
-    });
-    tokio::spawn(async move {
-        let mut rtcp_buf = vec![0u8; 1500];
-        while let Ok((_, _)) = rtp_sender_audio.read(&mut rtcp_buf).await {}
-    });
+/// Pins a viewer to a simulcast layer and to SVC layers, or back to automatic
+/// without `encodingId` nor layer ids
+#[post("/resource/{session_id}/layer")]
+async fn whep_layer(
+    auth: Option<BearerAuth>,
+    session_id: Path<String>,
+    request: web::Json<LayerRequest>,
+    whip_data: Data<WhipData>,
+) -> Result<impl Responder> {
+    let (session_id, session) =
+        viewer_session(auth, &session_id, &whip_data, "selecting a layer").await?;
+    if let Some(stream) = whip_data.streams.get(&session.stream_key).await {
+        stream
+            .select_layer(
+                &session_id,
+                request.media_id.as_deref(),
+                request.encoding_id.as_deref(),
+                svc_target(request.spatial_layer_id, request.temporal_layer_id),
+            )
+            .await?;
+    }
+    Ok(HttpResponse::Ok())
+}
+
+/// What a viewer gets and its bandwidth estimate
+#[get("/resource/{session_id}/stats")]
+async fn whep_stats(
+    auth: Option<BearerAuth>,
+    session_id: Path<String>,
+    whip_data: Data<WhipData>,
+) -> Result<impl Responder> {
+    let (session_id, session) =
+        viewer_session(auth, &session_id, &whip_data, "reading stats").await?;
+    let stats = match whip_data.streams.get(&session.stream_key).await {
+        Some(stream) => stream.viewer_stats(&session_id),
+        None => None,
+    };
+    Ok(web::Json(stats.ok_or(Error::SessionNotFound(session_id))?))
+}
+
+/// Live streams the viewer policies let the directory list
+#[get("/streams")]
+async fn stream_directory(whip_data: Data<WhipData>) -> Result<impl Responder> {
+    let policies = &whip_data.viewer_policies;
+    let live = whip_data
+        .streams
+        .live(|stream_key| policies.is_listed(stream_key))
+        .await;
+    Ok(web::Json(live))
+}
 
-    let mut subscriptions = whip_data.subscriptions.lock().await;
-    if let Some(subscribers) = subscriptions.get_mut(&stream_key) {
-        subscribers.push((video_track, audio_track));
+/// A listed stream, live or with viewers waiting for its publisher
+#[get("/streams/{stream}")]
+async fn stream_info(stream: Path<String>, whip_data: Data<WhipData>) -> Result<impl Responder> {
+    let stream_key = stream.into_inner();
+    // Unlisted streams are not told apart from missing ones
+    let info = if whip_data.viewer_policies.is_listed(&stream_key) {
+        whip_data.streams.info(&stream_key).await
     } else {
-        subscriptions.insert(stream_key, Vec::new());
-    }
+        None
+    };
+    Ok(web::Json(info.ok_or(Error::StreamNotFound(stream_key))?))
+}
 
-    pc.set_remote_description(RTCSessionDescription::offer(offer)?)
-        .await?;
-    let answer = pc.create_answer(None).await?;
-    pc.set_local_description(answer.clone()).await?;
+/// A viewer session, checked against the token it was created with
+async fn viewer_session(
+    auth: Option<BearerAuth>,
+    session_id: &str,
+    whip_data: &WhipData,
+    action: &str,
+) -> Result<(Uuid, Arc<Session>)> {
+    let session_id = Uuid::parse_str(session_id)?;
+    let session = whip_data
+        .sessions
+        .get(&session_id)
+        .await
+        .ok_or(Error::SessionNotFound(session_id))?;
+    if session.kind != SessionKind::Viewer {
+        return Err(Error::NotAViewer(session_id));
+    }
+    authorize_session(&session, auth, action)?;
+    Ok((session_id, session))
+}
 
-    pc.gathering_complete_promise().await.recv().await;
+/// Highest SVC layers asked for, every layer of a dimension left out
+fn svc_target(spatial: Option<u8>, temporal: Option<u8>) -> Option<svc::SvcLayer> {
+    if spatial.is_none() && temporal.is_none() {
+        return None;
+    }
+    Some(svc::SvcLayer {
+        spatial: spatial.unwrap_or(u8::MAX),
+        temporal: temporal.unwrap_or(u8::MAX),
+    })
+}
 
-    let mut whips = whip_data.whips.lock().await;
-    whips.insert(session_id, pc.clone());
+/// Rejects a body that is not of the media type a resource takes
+fn expect_content_type(req: &HttpRequest, media_type: &'static str) -> Result<()> {
+    if req.content_type() != media_type {
+        return Err(Error::UnsupportedMediaType(media_type));
+    }
+    Ok(())
+}
 
-    let late_answer = pc.local_description().await.unwrap().sdp;
+/// Closes the peer connection of a session which failed before being
+/// registered, as nothing else would
+async fn close_on_error<T>(pc: &RTCPeerConnection, result: Result<T>) -> Result<T> {
+    if result.is_err()
+        && let Err(err) = pc.close().await
+    {
+        eprintln!("Failed to close a session which failed to start: {err}");
+    }
+    result
+}
 
-    let mut res = HttpResponse::Created();
-    res.content_type("application/sdp");
+/// An offer parsed from a client's body
+fn parse_offer(offer: String) -> Result<RTCSessionDescription> {
+    RTCSessionDescription::offer(offer).map_err(|err| Error::MalformedSdp(err.to_string()))
+}
 
-    // Headers
-    res.insert_header(("Location", format!("/api/resource/{session_id}")));
-    for ice_server in whip_data.default_config.ice_servers.iter() {
-        for url in ice_server.urls.iter() {
-            res.insert_header(("Link", format!("<{url}>; rel=\"ice-server\";")));
-        }
+/// Bodies and query strings the extractors could not read
+fn json_error(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    match err {
+        JsonPayloadError::ContentType => Error::UnsupportedMediaType("application/json"),
+        err => Error::InvalidRequest(err.to_string()),
     }
+    .into()
+}
 
-    Ok(res.body(late_answer))
+fn query_error(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    Error::InvalidRequest(err.to_string()).into()
 }
 
-async fn not_found(req: HttpRequest) -> impl Responder {
-    dbg!(req);
-    HttpResponse::NotFound()
+async fn not_found(req: HttpRequest) -> Result<HttpResponse> {
+    Err(Error::RouteNotFound(format!(
+        "{} {}",
+        req.method(),
+        req.path()
+    )))
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     let args: Args = from_env();
+    let config = args
+        .config(&non_empty_env)
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+
+    if let Some(stream) = args.mint_token {
+        let Some(secret) = config.auth_hmac_secret else {
+            return Err(std::io::Error::other(
+                "Minting a token needs an auth hmac secret",
+            ));
+        };
+        let expires = auth::unix_now() + args.mint_ttl;
+        println!(
+            "{}",
+            HmacTokens::new(secret).sign(&stream, args.mint_action, expires)
+        );
+        return Ok(());
+    }
 
-    let config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: vec!["stun:stun.l.google.com:19302".to_owned()],
-            ..Default::default()
-        }],
+    config
+        .validate()
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    if args.print_config {
+        let mut config = config;
+        config.redact();
+        print!(
+            "{}",
+            toml::to_string(&config).map_err(std::io::Error::other)?
+        );
+        return Ok(());
+    }
+
+    let ice_servers = IceServers::new(config.ice_servers);
+    let rtc_config = RTCConfiguration {
+        ice_servers: ice_servers.stun_servers(),
         ..Default::default()
     };
 
@@ -435,53 +1315,113 @@ async fn main() -> std::io::Result<()> {
 
     // Settings
     let mut setting_engine = SettingEngine::default();
-
-    let mut web_port: Option<u16> = match env::var("PORT").ok() {
-        Some(port) => port.parse::<u16>().ok(),
-        None => None,
+    setting_engine.enable_sender_rtx(true);
+    let udp_mux_addresses = match config.udp_mux_port {
+        Some(_) => config.udp_mux_addresses.as_slice(),
+        None => &[],
     };
-    if let Some(port) = args.port {
-        web_port = Some(port);
+    config.network.apply(&mut setting_engine, udp_mux_addresses);
+
+    let mut ice_tcp_listeners = Vec::new();
+    if let Some(udp_port) = config.udp_mux_port {
+        let mut udp_muxes = Vec::new();
+        for ip in udp_mux_addresses {
+            let udp_socket =
+                UdpSocket::from_std(network::udp_socket(SocketAddr::new(*ip, udp_port))?)?;
+            let udp_mux_address = udp_socket.local_addr()?;
+            println!("Using UDP MUX on {udp_mux_address}");
+            udp_muxes.push(UDPMuxDefault::new(UDPMuxParams::new(udp_socket)));
+
+            if let Some(tcp_port) = config.ice_tcp_port {
+                let listener =
+                    IceTcpListener::bind(SocketAddr::new(*ip, tcp_port), udp_mux_address)?;
+                println!("Using ICE-TCP on {}", listener.local_addr()?);
+                ice_tcp_listeners.push(listener.local_addr()?);
+                tokio::spawn(listener.run());
+            }
+        }
+        let udp_mux: Arc<dyn UDPMux + Send + Sync> = match udp_muxes.len() {
+            1 => udp_muxes.remove(0),
+            _ => MultiUdpMux::new(udp_muxes),
+        };
+        setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
     }
-    let web_port = web_port.unwrap_or(8080);
 
-    let mut udp_mux_port: Option<u16> = match env::var("UDP_MUX_PORT").ok() {
-        Some(port) => port.parse::<u16>().ok(),
+    let authorizer: Arc<dyn StreamAuthorizer> = match (
+        config.auth_keys,
+        config.auth_hmac_secret,
+        config.auth_webhook,
+    ) {
+        (Some(path), _, _) => {
+            println!("Using stream keys from {}", path.display());
+            Arc::new(StaticKeys::load(&path)?)
+        }
+        (_, Some(secret), _) => {
+            println!("Using HMAC signed tokens");
+            Arc::new(HmacTokens::new(secret))
+        }
+        (_, _, Some(url)) => {
+            println!("Using authorization webhook: {url}");
+            Arc::new(Webhook::new(url))
+        }
+        (None, None, None) => {
+            println!("No authorization configured, stream keys are public");
+            Arc::new(AllowAll)
+        }
+    };
+
+    let viewer_policies = match config.viewer_policies {
+        Some(path) => {
+            println!("Using viewer policies from {}", path.display());
+            ViewerPolicies::load(&path)?
+        }
+        None => ViewerPolicies::default(),
+    };
+    let slate = match &config.slate {
+        Some(path) => {
+            println!("Using slate {}", path.display());
+            Some(Slate::load(path)?)
+        }
         None => None,
     };
-    if let Some(udp_port) = args.udp_mux_port {
-        udp_mux_port = Some(udp_port);
-    }
-    if let Some(udp_port) = udp_mux_port {
-        println!("Using UDP MUX port: {}", udp_port);
-        let udp_socket = UdpSocket::bind(("0.0.0.0", udp_port)).await.unwrap();
-        let udp_mux = UDPMuxDefault::new(UDPMuxParams::new(udp_socket));
-        setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
-    }
 
-    let mut nat_ips: Option<String> = env::var("NAT_IPS").ok();
-    if let Some(ips) = args.nat_ips
-        && !ips.is_empty()
-    {
-        nat_ips = Some(ips);
+    // NACKs from viewers are answered from the stream's retransmission buffer,
+    // so only the generator asking publishers for their lost packets is kept
+    for parameter in ["", "pli"] {
+        m.register_feedback(
+            RTCPFeedback {
+                typ: "nack".to_owned(),
+                parameter: parameter.to_owned(),
+            },
+            RTPCodecType::Video,
+        );
     }
-    if let Some(nat_ips) = nat_ips {
-        let nat_ips: Vec<String> = nat_ips
-            .split(',')
-            .map(|ip| ip.to_string())
-            .filter(|ip| !ip.is_empty())
-            .collect();
-        if !nat_ips.is_empty() {
-            println!("Using NAT 1 to 1 with IPs:");
-            for ip in nat_ips.clone().into_iter() {
-                println!(" - {}", ip);
-            }
-            setting_engine.set_nat_1to1_ips(nat_ips, RTCIceCandidateType::Host);
-        }
+    retransmit::register_rtx_codecs(&mut m).unwrap();
+    // Simulcast layers are told apart by their RTP stream id, AV1 SVC layers
+    // by their dependency descriptor
+    for uri in [
+        SDES_MID_URI,
+        SDES_RTP_STREAM_ID_URI,
+        SDES_REPAIR_RTP_STREAM_ID_URI,
+        svc::DEPENDENCY_DESCRIPTOR_URI,
+    ] {
+        m.register_header_extension(
+            RTCRtpHeaderExtensionCapability {
+                uri: uri.to_owned(),
+            },
+            RTPCodecType::Video,
+            None,
+        )
+        .unwrap();
     }
+    let rtx_streams = RtxStreams::default();
 
     let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut m).unwrap();
+    registry.add(Box::new(Generator::builder()));
+    registry.add(Box::new(rtx_streams.clone()));
+    registry = configure_rtcp_reports(registry);
+    // Viewers send transport-wide feedback on what they receive, feeding their estimates
+    registry = configure_twcc(registry, &mut m).unwrap();
     let api = APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
@@ -490,39 +1430,540 @@ async fn main() -> std::io::Result<()> {
 
     let whip_data = Data::new(WhipData {
         api: Arc::new(api),
-        default_config: config,
-        whips: Arc::new(Mutex::new(HashMap::new())),
-        subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        default_config: rtc_config,
+        authorizer,
+        viewer_policies: Arc::new(viewer_policies),
+        publisher_policy: config.publisher_policy,
+        publisher_policies: Arc::new(config.publisher_policies),
+        ice_servers: Arc::new(ice_servers),
+        sessions: SessionRegistry::default(),
+        streams: StreamRegistry::new(config.gop_cache, slate),
+        rtx_streams,
+        trickle_answer: config.trickle_answer,
+        gathering_timeout: Duration::from_secs(config.gathering_timeout),
+        ice_tcp_candidates: IceTcpCandidates::new(ice_tcp_listeners),
     });
 
-    println!("Listening on 0.0.0.0:{web_port}");
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(["OPTIONS", "POST", "DELETE", "PATCH"])
-            .allow_any_header();
+    whip_data.spawn_reaper(
+        Duration::from_secs(config.reap_grace),
+        Duration::from_secs(config.publisher_grace),
+    );
+
+    let mut listeners = Vec::new();
+    for ip in &config.bind_addresses {
+        let listener = network::tcp_listener(SocketAddr::new(*ip, config.port))?;
+        println!("Listening on {}", listener.local_addr()?);
+        listeners.push(listener);
+    }
+    let mut server = HttpServer::new(move || {
+        let cors = config
+            .cors_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+            .allowed_methods(["OPTIONS", "POST", "DELETE", "PATCH", "GET"])
+            .allow_any_header()
+            // Read by clients to PATCH and DELETE their session
+            .expose_headers([header::LOCATION, header::ETAG, header::LINK]);
+        let cors = if config.cors_origins.is_empty() {
+            cors.allow_any_origin()
+        } else {
+            cors
+        };
 
         App::new()
             .wrap(cors)
             .wrap(middleware::DefaultHeaders::new().add(("Permissions-Policy", "autoplay=(self)")))
             .app_data(Data::clone(&whip_data.clone()))
+            .app_data(web::JsonConfig::default().error_handler(json_error))
+            .app_data(web::QueryConfig::default().error_handler(query_error))
             .service(
                 web::scope("/api")
                     .service(whip_options)
                     .service(whip)
                     .service(whip_patch)
                     .service(whep)
-                    .service(whip_delete),
+                    .service(whep_layer)
+                    .service(whep_stats)
+                    .service(whip_delete)
+                    .service(stream_directory)
+                    .service(stream_info),
             )
             .service(
-                fs::Files::new("", "./static")
+                fs::Files::new("", &config.static_dir)
                     .show_files_listing()
                     .index_file("index.html")
                     .use_last_modified(true),
             )
             .default_service(web::to(not_found))
-    })
-    .bind(("0.0.0.0", web_port))?
-    .run()
-    .await
+    });
+    for listener in listeners {
+        server = server.listen(listener)?;
+    }
+    server.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whip_data() -> WhipData {
+        WhipData {
+            api: Arc::new(APIBuilder::new().build()),
+            default_config: RTCConfiguration::default(),
+            authorizer: Arc::new(AllowAll),
+            viewer_policies: Arc::new(ViewerPolicies::default()),
+            publisher_policy: PublisherPolicy::default(),
+            publisher_policies: Default::default(),
+            ice_servers: Arc::new(IceServers::new(Vec::new())),
+            sessions: SessionRegistry::default(),
+            streams: StreamRegistry::new(false, None),
+            rtx_streams: RtxStreams::default(),
+            trickle_answer: false,
+            gathering_timeout: Duration::from_secs(1),
+            ice_tcp_candidates: IceTcpCandidates::default(),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_environment_which_overrides_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("omniroom-{}-config.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "port = 9000\ngop_cache = true\ntrickle_answer = true\nreap_grace = 10\n",
+        )
+        .unwrap();
+        let config_path = path.to_str().unwrap().to_string();
+        let env = |key: &str| match key {
+            "OMNIROOM_CONFIG" => Some(config_path.clone()),
+            "PORT" => Some("9001".to_string()),
+            "REAP_GRACE" => Some("20".to_string()),
+            _ => None,
+        };
+
+        let args = Args::from_args(&["omniroom"], &[]).unwrap();
+        let config = args.config(&env).unwrap();
+        assert_eq!(config.port, 9001);
+        assert_eq!(config.reap_grace, 20);
+        assert!(config.gop_cache);
+        assert!(config.trickle_answer);
+
+        let args = Args::from_args(
+            &["omniroom"],
+            &["-p", "9002", "--gop-cache", "false", "--ipv6", "false"],
+        )
+        .unwrap();
+        let config = args.config(&env).unwrap();
+        assert_eq!(config.port, 9002);
+        assert_eq!(config.reap_grace, 20);
+        assert!(!config.gop_cache);
+        assert!(config.trickle_answer);
+        assert!(!config.network.ipv6);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn second_publisher_gets_a_conflict() {
+        let whip_data = whip_data();
+        let pc = Arc::new(
+            whip_data
+                .api
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        whip_data
+            .streams
+            .get_or_create("stream")
+            .await
+            .set_publisher(
+                Uuid::new_v4(),
+                pc,
+                Vec::new(),
+                HashMap::new(),
+                PublisherPolicy::Reject,
+            )
+            .unwrap();
+        let app =
+            actix_web::test::init_service(App::new().app_data(Data::new(whip_data)).service(whip))
+                .await;
+
+        let req = actix_web::test::TestRequest::post()
+            .uri("/whip")
+            .insert_header((header::CONTENT_TYPE, "application/sdp"))
+            .insert_header((header::AUTHORIZATION, "Bearer stream"))
+            .set_payload("v=0")
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn refusals_are_problem_details_with_a_challenge() {
+        let whip_data = WhipData {
+            authorizer: Arc::new(HmacTokens::new("s3cret")),
+            ..whip_data()
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(whip_data))
+                .service(whip)
+                .service(whep_stats),
+        )
+        .await;
+        let offer = || {
+            actix_web::test::TestRequest::post()
+                .uri("/whip")
+                .insert_header((header::CONTENT_TYPE, "application/sdp"))
+                .set_payload("v=0")
+        };
+
+        let res = actix_web::test::call_service(&app, offer().to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/problem+json"
+        );
+        assert_eq!(
+            res.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer realm=\"omniroom\""
+        );
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Unauthorized");
+        assert_eq!(body["status"], 401);
+        assert!(
+            body["detail"]
+                .as_str()
+                .unwrap()
+                .contains("missing bearer token")
+        );
+
+        let req = offer()
+            .insert_header((header::AUTHORIZATION, "Bearer forged"))
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let challenge = res.headers().get(header::WWW_AUTHENTICATE).unwrap();
+        assert!(
+            challenge
+                .to_str()
+                .unwrap()
+                .starts_with("Bearer realm=\"omniroom\", error=\"invalid_token\"")
+        );
+
+        let req = actix_web::test::TestRequest::get()
+            .uri(&format!("/resource/{}/stats", Uuid::new_v4()))
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.headers().get(header::WWW_AUTHENTICATE).is_none());
+        let body: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(body["title"], "Not Found");
+        assert_eq!(body["status"], 404);
+    }
+
+    #[actix_web::test]
+    async fn viewer_requests_need_the_viewer_token() {
+        let whip_data = whip_data();
+        let pc = whip_data
+            .api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let session_id = Uuid::new_v4();
+        whip_data
+            .register_session(
+                session_id,
+                Session::new(
+                    SessionKind::Viewer,
+                    "stream".to_string(),
+                    Arc::new(pc),
+                    Some("watch".to_string()),
+                ),
+            )
+            .await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(whip_data))
+                .service(whep_stats),
+        )
+        .await;
+        let stats = |token: Option<&str>| {
+            let req =
+                actix_web::test::TestRequest::get().uri(&format!("/resource/{session_id}/stats"));
+            match token {
+                Some(token) => {
+                    req.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                }
+                None => req,
+            }
+            .to_request()
+        };
+
+        let res = actix_web::test::call_service(&app, stats(None)).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = actix_web::test::call_service(&app, stats(Some("guess"))).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        // Past the token, there is no stream to report on
+        let res = actix_web::test::call_service(&app, stats(Some("watch"))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn publisher_deletes_its_session_with_its_expired_token() {
+        let tokens = HmacTokens::new("s3cret");
+        let expired = tokens.sign("stream", Action::Publish, auth::unix_now() - 60);
+        let whip_data = WhipData {
+            authorizer: Arc::new(tokens),
+            ..whip_data()
+        };
+        let pc = Arc::new(
+            whip_data
+                .api
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        let session_id = Uuid::new_v4();
+        whip_data
+            .register_session(
+                session_id,
+                Session::new(
+                    SessionKind::Publisher,
+                    "stream".to_string(),
+                    pc,
+                    Some(expired.clone()),
+                ),
+            )
+            .await;
+        let sessions = whip_data.sessions.clone();
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(whip_data))
+                .service(whip_delete),
+        )
+        .await;
+        let delete = |token: &str| {
+            actix_web::test::TestRequest::delete()
+                .uri(&format!("/resource/{session_id}"))
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_request()
+        };
+
+        let res = actix_web::test::call_service(&app, delete("stream")).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(sessions.get(&session_id).await.is_some());
+
+        let res = actix_web::test::call_service(&app, delete(&expired)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(sessions.get(&session_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn closed_session_leaves_the_registry() {
+        let whip_data = whip_data();
+        let pc = Arc::new(
+            whip_data
+                .api
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        let session_id = Uuid::new_v4();
+        whip_data.streams.get_or_create("stream").await;
+        whip_data
+            .register_session(
+                session_id,
+                Session::new(SessionKind::Viewer, "stream".to_string(), pc.clone(), None),
+            )
+            .await;
+
+        tokio::time::timeout(Duration::from_secs(5), pc.close())
+            .await
+            .expect("closing from the state change handler deadlocked")
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !whip_data.sessions.list().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the closed session is still registered");
+        assert!(whip_data.streams.get("stream").await.is_none());
+    }
+
+    /// Registers a viewer of "stream" whose connection never comes up
+    async fn add_viewer(whip_data: &WhipData) -> Uuid {
+        let pc = whip_data
+            .api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let session_id = Uuid::new_v4();
+        whip_data
+            .streams
+            .get_or_create("stream")
+            .await
+            .add_viewer(session_id, Vec::new());
+        whip_data
+            .register_session(
+                session_id,
+                Session::new(
+                    SessionKind::Viewer,
+                    "stream".to_string(),
+                    Arc::new(pc),
+                    None,
+                ),
+            )
+            .await;
+        session_id
+    }
+
+    #[tokio::test]
+    async fn reaper_ends_sessions_unhealthy_past_their_grace() {
+        let whip_data = whip_data();
+        let old = add_viewer(&whip_data).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let new = add_viewer(&whip_data).await;
+
+        whip_data
+            .reap(Duration::from_millis(100), Duration::from_secs(3600))
+            .await;
+        assert!(whip_data.sessions.get(&old).await.is_none());
+        assert!(whip_data.sessions.get(&new).await.is_some());
+    }
+
+    /// Starts publishing `stream_key` without any track
+    async fn publish(whip_data: &WhipData, stream_key: &str) -> Uuid {
+        let pc = whip_data
+            .api
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let session_id = Uuid::new_v4();
+        whip_data
+            .streams
+            .get_or_create(stream_key)
+            .await
+            .set_publisher(
+                session_id,
+                Arc::new(pc),
+                Vec::new(),
+                HashMap::new(),
+                PublisherPolicy::Reject,
+            )
+            .unwrap();
+        session_id
+    }
+
+    #[tokio::test]
+    async fn reaper_ends_viewers_whose_publisher_did_not_come_back() {
+        let whip_data = whip_data();
+        let viewer = add_viewer(&whip_data).await;
+        let publisher = publish(&whip_data, "stream").await;
+        whip_data.streams.remove_session("stream", &publisher).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let grace = Duration::from_secs(3600);
+        whip_data.reap(grace, grace).await;
+        assert!(whip_data.sessions.get(&viewer).await.is_some());
+        whip_data.reap(grace, Duration::from_millis(100)).await;
+        assert!(whip_data.sessions.get(&viewer).await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn directory_lists_only_visible_live_streams() {
+        let whip_data = WhipData {
+            viewer_policies: Arc::new(
+                toml::from_str(
+                    r#"
+                    [streams.backstage]
+                    policy = "token"
+                    tokens = ["crew-only"]
+
+                    [streams.rehearsal]
+                    policy = "public"
+                    listed = false
+                    "#,
+                )
+                .unwrap(),
+            ),
+            ..whip_data()
+        };
+        for stream_key in ["lobby", "backstage", "rehearsal"] {
+            publish(&whip_data, stream_key).await;
+        }
+        add_viewer(&whip_data).await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(Data::new(whip_data))
+                .service(stream_directory)
+                .service(stream_info),
+        )
+        .await;
+        let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri).to_request();
+
+        let res = actix_web::test::call_service(&app, get("/streams")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let listed: serde_json::Value = actix_web::test::read_body_json(res).await;
+        let ids: Vec<_> = listed
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|info| info["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["lobby"]);
+
+        for unlisted in [
+            "/streams/backstage",
+            "/streams/rehearsal",
+            "/streams/nowhere",
+        ] {
+            let res = actix_web::test::call_service(&app, get(unlisted)).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{unlisted}");
+        }
+        // Viewers waiting on a listed stream can still look it up
+        let res = actix_web::test::call_service(&app, get("/streams/stream")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(info["state"], "waiting");
+        assert_eq!(info["viewers"], 1);
+    }
+
+    #[actix_web::test]
+    async fn refused_viewers_leave_no_stream_behind() {
+        let whip_data = WhipData {
+            viewer_policies: Arc::new(
+                toml::from_str(
+                    r#"
+                    [streams.backstage]
+                    policy = "token"
+                    tokens = ["crew-only"]
+                    "#,
+                )
+                .unwrap(),
+            ),
+            ..whip_data()
+        };
+        let streams = whip_data.streams.clone();
+        let app =
+            actix_web::test::init_service(App::new().app_data(Data::new(whip_data)).service(whep))
+                .await;
+        let offer = |stream_key: &str, sdp: &'static str| {
+            actix_web::test::TestRequest::post()
+                .uri(&format!("/whep/{stream_key}"))
+                .insert_header((header::CONTENT_TYPE, "application/sdp"))
+                .set_payload(sdp)
+                .to_request()
+        };
+
+        let res = actix_web::test::call_service(&app, offer("backstage", "v=0")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(streams.get("backstage").await.is_none());
+        let res = actix_web::test::call_service(&app, offer("lobby", "not an offer")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(streams.get("lobby").await.is_none());
+    }
 }
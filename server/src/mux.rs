@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use tokio::{sync::mpsc, task::JoinHandle};
+use webrtc::{
+    ice::udp_mux::{UDPMux, UDPMuxDefault},
+    util::Conn,
+};
+
+/// Packets read ahead from the sockets of a [`MultiConn`]
+const RECEIVE_QUEUE: usize = 256;
+
+/// UDP mux over several sockets sharing a port, one per bind address (e.g.
+/// `0.0.0.0` and `[::]`), as [`UDPMuxDefault`] only takes one
+pub struct MultiUdpMux {
+    muxes: Vec<Arc<UDPMuxDefault>>,
+    conns: Mutex<HashMap<String, Arc<MultiConn>>>,
+}
+
+impl MultiUdpMux {
+    pub fn new(muxes: Vec<Arc<UDPMuxDefault>>) -> Arc<Self> {
+        Arc::new(Self {
+            muxes,
+            conns: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl UDPMux for MultiUdpMux {
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for mux in &self.muxes {
+            mux.close().await?;
+        }
+        Ok(())
+    }
+
+    async fn get_conn(
+        self: Arc<Self>,
+        ufrag: &str,
+    ) -> webrtc::util::Result<Arc<dyn Conn + Send + Sync>> {
+        if let Some(conn) = self.conns.lock().unwrap().get(ufrag) {
+            return Ok(conn.clone());
+        }
+        let mut conns = Vec::with_capacity(self.muxes.len());
+        for mux in &self.muxes {
+            conns.push(mux.clone().get_conn(ufrag).await?);
+        }
+        let conn = self
+            .conns
+            .lock()
+            .unwrap()
+            .entry(ufrag.to_string())
+            .or_insert_with(|| Arc::new(MultiConn::new(conns)))
+            .clone();
+        Ok(conn)
+    }
+
+    async fn remove_conn_by_ufrag(&self, ufrag: &str) {
+        self.conns.lock().unwrap().remove(ufrag);
+        for mux in &self.muxes {
+            mux.remove_conn_by_ufrag(ufrag).await;
+        }
+    }
+}
+
+/// The connections of one ICE session on every socket, sending through the
+/// one the remote address was last heard on, or else one of its family
+struct MultiConn {
+    conns: Vec<Arc<dyn Conn + Send + Sync>>,
+    received: tokio::sync::Mutex<mpsc::Receiver<(Vec<u8>, SocketAddr, usize)>>,
+    routes: Mutex<HashMap<SocketAddr, usize>>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl MultiConn {
+    fn new(conns: Vec<Arc<dyn Conn + Send + Sync>>) -> Self {
+        let (tx, rx) = mpsc::channel(RECEIVE_QUEUE);
+        let readers = conns
+            .iter()
+            .enumerate()
+            .map(|(index, conn)| {
+                let (conn, tx) = (conn.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let mut buf = vec![0; usize::from(u16::MAX)];
+                    while let Ok((len, addr)) = conn.recv_from(&mut buf).await {
+                        if tx.send((buf[..len].to_vec(), addr, index)).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            })
+            .collect();
+        Self {
+            conns,
+            received: tokio::sync::Mutex::new(rx),
+            routes: Mutex::new(HashMap::new()),
+            readers,
+        }
+    }
+
+    fn route(&self, target: SocketAddr) -> Option<&Arc<dyn Conn + Send + Sync>> {
+        if let Some(index) = self.routes.lock().unwrap().get(&target) {
+            return self.conns.get(*index);
+        }
+        self.conns.iter().find(|conn| {
+            conn.local_addr()
+                .is_ok_and(|local| local.is_ipv4() == target.is_ipv4())
+        })
+    }
+}
+
+impl Drop for MultiConn {
+    fn drop(&mut self) {
+        for reader in &self.readers {
+            reader.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Conn for MultiConn {
+    async fn connect(&self, _addr: SocketAddr) -> webrtc::util::Result<()> {
+        Err(io::Error::other("Not applicable").into())
+    }
+
+    async fn recv(&self, _buf: &mut [u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::other("Not applicable").into())
+    }
+
+    async fn recv_from(&self, buf: &mut [u8]) -> webrtc::util::Result<(usize, SocketAddr)> {
+        let (packet, addr, index) = self
+            .received
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+        self.routes.lock().unwrap().insert(addr, index);
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok((len, addr))
+    }
+
+    async fn send(&self, _buf: &[u8]) -> webrtc::util::Result<usize> {
+        Err(io::Error::other("Not applicable").into())
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> webrtc::util::Result<usize> {
+        let conn = self
+            .route(target)
+            .ok_or_else(|| io::Error::other(format!("no UDP mux socket can reach {target}")))?;
+        conn.send_to(buf, target).await
+    }
+
+    /// Every socket has the same port, which is what ICE uses
+    fn local_addr(&self) -> webrtc::util::Result<SocketAddr> {
+        self.conns[0].local_addr()
+    }
+
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    async fn close(&self) -> webrtc::util::Result<()> {
+        for conn in &self.conns {
+            conn.close().await?;
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &(dyn std::any::Any + Send + Sync) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+    use webrtc::{
+        ice::udp_mux::UDPMuxParams,
+        stun::{
+            agent::TransactionId,
+            attributes::ATTR_USERNAME,
+            message::{BINDING_REQUEST, Message},
+            textattrs::Username,
+        },
+    };
+
+    use super::*;
+
+    fn binding_request(ufrag: &str) -> Vec<u8> {
+        let mut message = Message::new();
+        message
+            .build(&[
+                Box::new(BINDING_REQUEST),
+                Box::new(TransactionId::new()),
+                Box::new(Username::new(ATTR_USERNAME, format!("{ufrag}:remote"))),
+            ])
+            .unwrap();
+        message.raw
+    }
+
+    #[tokio::test]
+    async fn sessions_are_reached_and_answered_on_every_socket() {
+        let ipv4 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = ipv4.local_addr().unwrap().port();
+        let ipv6 = UdpSocket::bind(("::1", port)).await.unwrap();
+        let locals = [ipv4.local_addr().unwrap(), ipv6.local_addr().unwrap()];
+        let mux = MultiUdpMux::new(vec![
+            UDPMuxDefault::new(UDPMuxParams::new(ipv4)),
+            UDPMuxDefault::new(UDPMuxParams::new(ipv6)),
+        ]);
+        let conn = mux.clone().get_conn("session").await.unwrap();
+        let other = mux.clone().get_conn("other").await.unwrap();
+        assert_eq!(conn.local_addr().unwrap(), locals[0]);
+
+        let mut buf = vec![0; 1500];
+        for local in locals {
+            let remote = UdpSocket::bind(SocketAddr::new(local.ip(), 0))
+                .await
+                .unwrap();
+            let request = binding_request("session");
+            remote.send_to(&request, local).await.unwrap();
+            let (len, from) =
+                tokio::time::timeout(Duration::from_secs(1), conn.recv_from(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(from, remote.local_addr().unwrap());
+            assert_eq!(buf[..len], request);
+
+            conn.send_to(b"reply", from).await.unwrap();
+            let (len, replier) = remote.recv_from(&mut buf).await.unwrap();
+            assert_eq!(replier, local);
+            assert_eq!(&buf[..len], b"reply");
+        }
+        // Nothing went to the other session on the same sockets
+        let nothing =
+            tokio::time::timeout(Duration::from_millis(20), other.recv_from(&mut buf)).await;
+        assert!(nothing.is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU16, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use webrtc::{
+    api::media_engine::MediaEngine,
+    interceptor::{
+        Attributes, Interceptor, InterceptorBuilder, RTCPReader, RTCPWriter, RTPReader, RTPWriter,
+        stream_info::StreamInfo,
+    },
+    rtp::packet::Packet,
+    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+};
+
+/// RTX payload types paired with the video payload types of the default codecs
+const RTX_PAYLOAD_TYPES: [(u8, u8); 9] = [
+    (97, 96),
+    (99, 98),
+    (101, 100),
+    (103, 102),
+    (121, 127),
+    (124, 125),
+    (109, 108),
+    (122, 123),
+    (42, 41),
+];
+
+/// How many recent packets of a track can be retransmitted, a power of two
+const RETRANSMIT_BUFFER_SIZE: usize = 1024;
+
+/// Recent packets of a published track, to answer viewers' NACKs (RFC 4585)
+pub struct RetransmitBuffer {
+    packets: Vec<Option<Arc<Packet>>>,
+}
+
+impl Default for RetransmitBuffer {
+    fn default() -> Self {
+        Self {
+            packets: vec![None; RETRANSMIT_BUFFER_SIZE],
+        }
+    }
+}
+
+impl RetransmitBuffer {
+    pub fn push(&mut self, packet: &Arc<Packet>) {
+        let index = packet.header.sequence_number as usize % RETRANSMIT_BUFFER_SIZE;
+        self.packets[index] = Some(packet.clone());
+    }
+
+    pub fn get(&self, sequence_number: u16) -> Option<Arc<Packet>> {
+        self.packets[sequence_number as usize % RETRANSMIT_BUFFER_SIZE]
+            .as_ref()
+            .filter(|packet| packet.header.sequence_number == sequence_number)
+            .cloned()
+    }
+}
+
+/// Offers an RTX stream along each default video codec, to be called once the
+/// video feedback is registered as RTX streams carry none
+pub fn register_rtx_codecs(media_engine: &mut MediaEngine) -> webrtc::error::Result<()> {
+    for (payload_type, associated_payload_type) in RTX_PAYLOAD_TYPES {
+        media_engine.register_codec(
+            RTCRtpCodecParameters {
+                capability: RTCRtpCodecCapability {
+                    mime_type: "video/rtx".to_owned(),
+                    clock_rate: 90000,
+                    channels: 0,
+                    sdp_fmtp_line: format!("apt={associated_payload_type}"),
+                    rtcp_feedback: vec![],
+                },
+                payload_type,
+                ..Default::default()
+            },
+            RTPCodecType::Video,
+        )?;
+    }
+    Ok(())
+}
+
+/// An RTX stream (RFC 4588) negotiated with a viewer
+pub struct RtxStream {
+    ssrc: u32,
+    payload_type: u8,
+    sequence_number: AtomicU16,
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+}
+
+impl RtxStream {
+    /// Sends `packet` again, wrapped with its original sequence number
+    pub async fn retransmit(&self, packet: &Packet) -> Result<usize, webrtc::interceptor::Error> {
+        let mut payload = Vec::with_capacity(2 + packet.payload.len());
+        payload.extend_from_slice(&packet.header.sequence_number.to_be_bytes());
+        payload.extend_from_slice(&packet.payload);
+
+        let mut header = packet.header.clone();
+        header.ssrc = self.ssrc;
+        header.payload_type = self.payload_type;
+        header.sequence_number = self.sequence_number.fetch_add(1, Ordering::Relaxed);
+        let rtx = Packet {
+            header,
+            payload: payload.into(),
+        };
+        self.writer.write(&rtx, &Attributes::new()).await
+    }
+}
+
+/// RTX streams of every viewer, by the SSRC of the stream they repair
+#[derive(Clone, Default)]
+pub struct RtxStreams {
+    streams: Arc<std::sync::Mutex<HashMap<u32, Arc<RtxStream>>>>,
+}
+
+impl RtxStreams {
+    pub fn get(&self, media_ssrc: u32) -> Option<Arc<RtxStream>> {
+        self.streams.lock().unwrap().get(&media_ssrc).cloned()
+    }
+}
+
+impl InterceptorBuilder for RtxStreams {
+    fn build(
+        &self,
+        _id: &str,
+    ) -> Result<Arc<dyn Interceptor + Send + Sync>, webrtc::interceptor::Error> {
+        Ok(Arc::new(RtxInterceptor {
+            streams: self.clone(),
+        }))
+    }
+}
+
+/// Collects the writers of outgoing RTX streams, which webrtc keeps to itself
+struct RtxInterceptor {
+    streams: RtxStreams,
+}
+
+#[async_trait]
+impl Interceptor for RtxInterceptor {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        reader
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        if let Some(media) = &info.associated_stream
+            && info.mime_type.ends_with("/rtx")
+        {
+            let stream = Arc::new(RtxStream {
+                ssrc: info.ssrc,
+                payload_type: info.payload_type,
+                sequence_number: AtomicU16::new(rand_sequence_number(info.ssrc)),
+                writer: writer.clone(),
+            });
+            self.streams
+                .streams
+                .lock()
+                .unwrap()
+                .insert(media.ssrc, stream);
+        }
+        writer
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        if let Some(media) = &info.associated_stream {
+            self.streams.streams.lock().unwrap().remove(&media.ssrc);
+        }
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<(), webrtc::interceptor::Error> {
+        Ok(())
+    }
+}
+
+/// RTX sequence numbers start at a value derived from the random SSRC
+fn rand_sequence_number(ssrc: u32) -> u16 {
+    (ssrc ^ (ssrc >> 16)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(sequence_number: u16) -> Arc<Packet> {
+        Arc::new(Packet {
+            header: Header {
+                sequence_number,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn found(buffer: &RetransmitBuffer, sequence_number: u16) -> Option<u16> {
+        buffer
+            .get(sequence_number)
+            .map(|packet| packet.header.sequence_number)
+    }
+
+    #[test]
+    fn packets_are_found_across_the_wrap() {
+        let mut buffer = RetransmitBuffer::default();
+        for sequence_number in [65534, 65535, 0, 1] {
+            buffer.push(&packet(sequence_number));
+        }
+        for sequence_number in [65534, 65535, 0, 1] {
+            assert_eq!(found(&buffer, sequence_number), Some(sequence_number));
+        }
+    }
+
+    #[test]
+    fn evicted_and_unknown_packets_are_not_found() {
+        let mut buffer = RetransmitBuffer::default();
+        assert_eq!(found(&buffer, 0), None);
+
+        let size = RETRANSMIT_BUFFER_SIZE as u16;
+        for sequence_number in 0..=size {
+            buffer.push(&packet(sequence_number));
+        }
+        // Replaced by the packet a buffer size later
+        assert_eq!(found(&buffer, 0), None);
+        assert_eq!(found(&buffer, size), Some(size));
+        assert_eq!(found(&buffer, 1), Some(1));
+        // Same slot as a buffered packet, but never sent
+        assert_eq!(found(&buffer, 1 + 2 * size), None);
+        assert_eq!(found(&buffer, size + 1), None);
+    }
+}
@@ -0,0 +1,245 @@
+use std::{borrow::Cow, time::Instant};
+
+use webrtc::rtp::packet::Packet;
+
+/// How many sent packets can be traced back to their source, for retransmissions
+const HISTORY_SIZE: usize = 1024;
+
+/// Renumbers packets from successive sources into one continuous stream
+///
+/// Each source numbers its packets and timestamps from a random start, so
+/// switching sources without rewriting looks like massive loss to receivers.
+#[derive(Clone, Debug)]
+pub struct RtpRewriter {
+    clock_rate: u32,
+    sequence_offset: u16,
+    timestamp_offset: u32,
+    /// Newest packet sent: sequence number, timestamp and when
+    last: Option<(u16, u32, Instant)>,
+    /// SSRC of the source being rewritten, a new one rebases the stream
+    source: Option<u32>,
+    rebase: bool,
+    /// Bumped on each rebase, older packets are from another source
+    generation: u32,
+    /// Sent packets as `(sequence number, source sequence number, generation)`
+    history: Vec<Option<(u16, u16, u32)>>,
+}
+
+impl RtpRewriter {
+    pub fn new(clock_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            sequence_offset: 0,
+            timestamp_offset: 0,
+            last: None,
+            source: None,
+            rebase: false,
+            generation: 0,
+            history: vec![None; HISTORY_SIZE],
+        }
+    }
+
+    /// Makes the next packet continue the stream, whatever its numbering
+    pub fn rebase(&mut self) {
+        self.rebase = true;
+    }
+
+    pub fn rewrite<'a>(&mut self, packet: &'a Packet) -> Cow<'a, Packet> {
+        if self
+            .source
+            .replace(packet.header.ssrc)
+            .is_some_and(|source| source != packet.header.ssrc)
+        {
+            self.rebase = true;
+        }
+        if std::mem::take(&mut self.rebase)
+            && let Some((sequence_number, timestamp, at)) = self.last
+        {
+            let elapsed = (at.elapsed().as_secs_f64() * f64::from(self.clock_rate)) as u32;
+            self.sequence_offset = sequence_number
+                .wrapping_add(1)
+                .wrapping_sub(packet.header.sequence_number);
+            self.timestamp_offset = timestamp
+                .wrapping_add(elapsed.max(1))
+                .wrapping_sub(packet.header.timestamp);
+            self.generation = self.generation.wrapping_add(1);
+        }
+
+        let original = packet.header.sequence_number;
+        let packet = self.renumber(packet);
+        let (sequence_number, timestamp) = (packet.header.sequence_number, packet.header.timestamp);
+        self.history[sequence_number as usize % HISTORY_SIZE] =
+            Some((sequence_number, original, self.generation));
+        if self
+            .last
+            .is_none_or(|(last, _, _)| sequence_number.wrapping_sub(last) < 0x8000)
+        {
+            self.last = Some((sequence_number, timestamp, Instant::now()));
+        }
+        packet
+    }
+
+    /// Leaves the next packet out, the ones after it taking its number
+    pub fn skip(&mut self) {
+        self.sequence_offset = self.sequence_offset.wrapping_sub(1);
+    }
+
+    fn renumber<'a>(&self, packet: &'a Packet) -> Cow<'a, Packet> {
+        if self.sequence_offset == 0 && self.timestamp_offset == 0 {
+            return Cow::Borrowed(packet);
+        }
+        let mut packet = packet.clone();
+        packet.header.sequence_number = packet
+            .header
+            .sequence_number
+            .wrapping_add(self.sequence_offset);
+        packet.header.timestamp = packet.header.timestamp.wrapping_add(self.timestamp_offset);
+        Cow::Owned(packet)
+    }
+
+    /// Source sequence number of a packet sent since the last rebase
+    pub fn original_sequence_number(&self, sequence_number: u16) -> Option<u16> {
+        match self.history[sequence_number as usize % HISTORY_SIZE] {
+            Some((sent, original, generation))
+                if sent == sequence_number && generation == self.generation =>
+            {
+                Some(original)
+            }
+            _ => None,
+        }
+    }
+
+    /// Source packet numbered again as it was first sent
+    pub fn resend(&self, sequence_number: u16, packet: &Packet) -> Packet {
+        let mut packet = packet.clone();
+        packet.header.sequence_number = sequence_number;
+        packet.header.timestamp = packet.header.timestamp.wrapping_add(self.timestamp_offset);
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    fn packet(ssrc: u32, sequence_number: u16, timestamp: u32) -> Packet {
+        Packet {
+            header: Header {
+                ssrc,
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn numbers(rewriter: &mut RtpRewriter, packet: &Packet) -> (u16, u32) {
+        let packet = rewriter.rewrite(packet);
+        (packet.header.sequence_number, packet.header.timestamp)
+    }
+
+    #[test]
+    fn first_source_passes_through() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        assert_eq!(numbers(&mut rewriter, &packet(1, 100, 1000)), (100, 1000));
+        assert_eq!(numbers(&mut rewriter, &packet(1, 101, 4000)), (101, 4000));
+    }
+
+    #[test]
+    fn new_source_follows_on() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 100, 1000));
+        numbers(&mut rewriter, &packet(1, 101, 4000));
+
+        let (sequence_number, timestamp) = numbers(&mut rewriter, &packet(2, 30_000, 500));
+        assert_eq!(sequence_number, 102);
+        assert!(timestamp > 4000);
+        // The offsets stick for the rest of the new source
+        assert_eq!(
+            numbers(&mut rewriter, &packet(2, 30_001, 3500)),
+            (103, timestamp + 3000)
+        );
+    }
+
+    #[test]
+    fn explicit_rebase_with_same_source() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 10, 1000));
+        rewriter.rebase();
+        assert_eq!(numbers(&mut rewriter, &packet(1, 5000, 1000)).0, 11);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 65_534, u32::MAX - 1000));
+        numbers(&mut rewriter, &packet(1, 65_535, u32::MAX));
+
+        let (sequence_number, timestamp) = numbers(&mut rewriter, &packet(2, 7, 1_000_000));
+        assert_eq!(sequence_number, 0);
+        assert!(timestamp < 1_000_000);
+        assert_eq!(numbers(&mut rewriter, &packet(2, 8, 1_000_000)).0, 1);
+        assert_eq!(rewriter.original_sequence_number(65_535), None);
+        assert_eq!(rewriter.original_sequence_number(0), Some(7));
+    }
+
+    #[test]
+    fn late_packet_does_not_move_the_base() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 10, 1000));
+        numbers(&mut rewriter, &packet(1, 12, 1000));
+        numbers(&mut rewriter, &packet(1, 11, 1000));
+        assert_eq!(numbers(&mut rewriter, &packet(2, 500, 0)).0, 13);
+    }
+
+    #[test]
+    fn skipped_packets_leave_no_gap() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 10, 1000));
+        rewriter.skip();
+        assert_eq!(numbers(&mut rewriter, &packet(1, 12, 1000)).0, 11);
+
+        assert_eq!(rewriter.original_sequence_number(10), Some(10));
+        assert_eq!(rewriter.original_sequence_number(11), Some(12));
+        // Never sent under this number
+        assert_eq!(rewriter.original_sequence_number(12), None);
+    }
+
+    #[test]
+    fn history_ages_out() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        for sequence_number in 0..=HISTORY_SIZE as u16 {
+            numbers(&mut rewriter, &packet(1, sequence_number, 0));
+        }
+        assert_eq!(rewriter.original_sequence_number(0), None);
+        assert_eq!(rewriter.original_sequence_number(1), Some(1));
+        assert_eq!(
+            rewriter.original_sequence_number(HISTORY_SIZE as u16),
+            Some(HISTORY_SIZE as u16)
+        );
+    }
+
+    #[test]
+    fn history_forgets_previous_sources() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 10, 0));
+        numbers(&mut rewriter, &packet(2, 900, 0));
+        assert_eq!(rewriter.original_sequence_number(10), None);
+        assert_eq!(rewriter.original_sequence_number(11), Some(900));
+    }
+
+    #[test]
+    fn resend_keeps_the_sent_numbers() {
+        let mut rewriter = RtpRewriter::new(90_000);
+        numbers(&mut rewriter, &packet(1, 10, 1000));
+        let (sequence_number, timestamp) = numbers(&mut rewriter, &packet(2, 900, 50));
+        let resent = rewriter.resend(sequence_number, &packet(2, 900, 50));
+        assert_eq!(
+            (resent.header.sequence_number, resent.header.timestamp),
+            (sequence_number, timestamp)
+        );
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashSet;
+
+use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+
+/// A trickle ICE SDP fragment (RFC 8840) sent by a client in a PATCH
+pub struct TrickleFragment {
+    pub ufrag: String,
+    pub pwd: String,
+    /// Candidates with the media section they were listed under, the empty
+    /// candidate standing for `a=end-of-candidates`
+    pub candidates: Vec<RTCIceCandidateInit>,
+}
+
+impl TrickleFragment {
+    pub fn parse(fragment: &str) -> Option<Self> {
+        let mut ufrag = None;
+        let mut pwd = None;
+        let mut candidates = Vec::new();
+        let mut mid = None;
+        let mut mline_index: Option<u16> = None;
+        for line in fragment.lines() {
+            if line.starts_with("m=") {
+                mline_index = Some(mline_index.map_or(0, |index| index + 1));
+                mid = None;
+            } else if let Some(value) = line.strip_prefix("a=mid:") {
+                mid = Some(value.to_string());
+            } else if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+                ufrag.get_or_insert_with(|| value.to_string());
+            } else if let Some(value) = line.strip_prefix("a=ice-pwd:") {
+                pwd.get_or_insert_with(|| value.to_string());
+            } else if line.starts_with("a=candidate:") || line == "a=end-of-candidates" {
+                candidates.push(RTCIceCandidateInit {
+                    candidate: line
+                        .strip_prefix("a=")
+                        .filter(|candidate| candidate.starts_with("candidate:"))
+                        .unwrap_or_default()
+                        .to_string(),
+                    sdp_mid: mid.clone(),
+                    sdp_mline_index: mline_index,
+                    username_fragment: None,
+                });
+            }
+        }
+
+        let ufrag = ufrag?;
+        for candidate in &mut candidates {
+            candidate.username_fragment = Some(ufrag.clone());
+        }
+        Some(Self {
+            ufrag,
+            pwd: pwd?,
+            candidates,
+        })
+    }
+}
+
+/// ICE ufrag of a description, which identifies its ICE session
+pub fn ice_ufrag(sdp: &str) -> Option<&str> {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix("a=ice-ufrag:"))
+}
+
+/// A description with new ICE credentials and none of the previous
+/// candidates, to restart ICE from
+pub fn with_credentials(sdp: &str, ufrag: &str, pwd: &str) -> String {
+    let mut restarted = String::with_capacity(sdp.len());
+    for line in sdp.lines() {
+        if line.starts_with("a=candidate:") || line == "a=end-of-candidates" {
+            continue;
+        }
+        if line.starts_with("a=ice-ufrag:") {
+            restarted.push_str(&format!("a=ice-ufrag:{ufrag}"));
+        } else if line.starts_with("a=ice-pwd:") {
+            restarted.push_str(&format!("a=ice-pwd:{pwd}"));
+        } else {
+            restarted.push_str(line);
+        }
+        restarted.push_str("\r\n");
+    }
+    restarted
+}
+
+/// Fragment of the local description for a client: the bundle group and the
+/// first media section with its ICE credentials, and the candidates not
+/// `delivered` yet, which are returned
+pub fn local_fragment(
+    sdp: &str,
+    delivered: &HashSet<String>,
+    complete: bool,
+) -> (String, Vec<String>) {
+    let mut fragment = String::new();
+    let mut candidates = Vec::new();
+    let mut media_sections = 0;
+    for line in sdp.lines() {
+        if line.starts_with("m=") {
+            media_sections += 1;
+        }
+        let keep = match media_sections {
+            0 => line.starts_with("a=group:") || line.starts_with("a=ice-lite"),
+            // Everything is bundled on the first media section
+            1 => {
+                line.starts_with("m=")
+                    || line.starts_with("a=mid:")
+                    || line.starts_with("a=ice-ufrag:")
+                    || line.starts_with("a=ice-pwd:")
+            }
+            _ => false,
+        };
+        if keep {
+            fragment.push_str(line);
+            fragment.push_str("\r\n");
+        }
+        if media_sections == 1
+            && let Some(candidate) = line.strip_prefix("a=")
+            && candidate.starts_with("candidate:")
+            && !delivered.contains(candidate)
+        {
+            fragment.push_str(line);
+            fragment.push_str("\r\n");
+            candidates.push(candidate.to_string());
+        }
+    }
+    if complete {
+        fragment.push_str("a=end-of-candidates\r\n");
+    }
+    (fragment, candidates)
+}
+
+/// Candidates of the local description, as sent to the client
+pub fn local_candidates(sdp: &str) -> HashSet<String> {
+    sdp.lines()
+        .filter_map(|line| line.strip_prefix("a="))
+        .filter(|line| line.starts_with("candidate:"))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host";
+    const SRFLX: &str =
+        "candidate:2 1 udp 1694498815 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 0";
+
+    const ANSWER: &str = "v=0\r\n\
+        o=- 1 2 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        a=group:BUNDLE 0 1\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=mid:0\r\n\
+        a=ice-ufrag:server\r\n\
+        a=ice-pwd:serverpassword\r\n\
+        a=candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host\r\n\
+        a=candidate:2 1 udp 1694498815 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 0\r\n\
+        a=end-of-candidates\r\n\
+        a=rtpmap:111 opus/48000/2\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        a=mid:1\r\n\
+        a=ice-ufrag:server\r\n\
+        a=ice-pwd:serverpassword\r\n\
+        a=candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host\r\n";
+
+    #[test]
+    fn parses_candidates_by_media_section() {
+        let fragment = TrickleFragment::parse(
+            "a=ice-ufrag:client\r\n\
+             a=ice-pwd:clientpassword\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 0\r\n\
+             a=mid:0\r\n\
+             a=candidate:1 1 udp 2130706431 192.168.1.2 50000 typ host\r\n\
+             m=video 9 UDP/TLS/RTP/SAVPF 0\r\n\
+             a=mid:1\r\n\
+             a=candidate:2 1 udp 1694498815 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 0\r\n",
+        )
+        .unwrap();
+        assert_eq!(fragment.ufrag, "client");
+        assert_eq!(fragment.pwd, "clientpassword");
+        let candidates: Vec<_> = fragment
+            .candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    candidate.candidate.as_str(),
+                    candidate.sdp_mid.as_deref(),
+                    candidate.sdp_mline_index,
+                    candidate.username_fragment.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            candidates,
+            [
+                (HOST, Some("0"), Some(0), Some("client")),
+                (SRFLX, Some("1"), Some(1), Some("client")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_end_of_candidates() {
+        let fragment = TrickleFragment::parse(
+            "a=ice-ufrag:client\r\n\
+             a=ice-pwd:clientpassword\r\n\
+             m=audio 9 UDP/TLS/RTP/SAVPF 0\r\n\
+             a=mid:0\r\n\
+             a=end-of-candidates\r\n",
+        )
+        .unwrap();
+        assert_eq!(fragment.candidates.len(), 1);
+        assert_eq!(fragment.candidates[0].candidate, "");
+        assert_eq!(fragment.candidates[0].sdp_mid.as_deref(), Some("0"));
+    }
+
+    #[test]
+    fn needs_credentials() {
+        assert!(
+            TrickleFragment::parse("a=ice-pwd:clientpassword\r\na=end-of-candidates\r\n").is_none()
+        );
+        assert!(TrickleFragment::parse("a=ice-ufrag:client\r\na=end-of-candidates\r\n").is_none());
+        assert!(TrickleFragment::parse("").is_none());
+    }
+
+    #[test]
+    fn finds_the_ice_ufrag() {
+        assert_eq!(ice_ufrag(ANSWER), Some("server"));
+        assert_eq!(ice_ufrag("v=0\r\n"), None);
+    }
+
+    #[test]
+    fn restarts_with_new_credentials() {
+        let restarted = with_credentials(ANSWER, "fresh", "freshpassword");
+        assert!(!restarted.contains("a=candidate:"));
+        assert!(!restarted.contains("a=end-of-candidates"));
+        assert!(!restarted.contains("server"));
+        assert_eq!(restarted.matches("a=ice-ufrag:fresh\r\n").count(), 2);
+        assert_eq!(restarted.matches("a=ice-pwd:freshpassword\r\n").count(), 2);
+        assert!(restarted.contains("a=rtpmap:111 opus/48000/2\r\n"));
+        assert!(restarted.ends_with("\r\n"));
+    }
+
+    #[test]
+    fn local_fragment_of_the_first_media_section() {
+        let (fragment, candidates) = local_fragment(ANSWER, &HashSet::new(), false);
+        assert_eq!(
+            fragment,
+            format!(
+                "a=group:BUNDLE 0 1\r\n\
+                 m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+                 a=mid:0\r\n\
+                 a=ice-ufrag:server\r\n\
+                 a=ice-pwd:serverpassword\r\n\
+                 a={HOST}\r\n\
+                 a={SRFLX}\r\n"
+            )
+        );
+        assert_eq!(candidates, [HOST, SRFLX]);
+    }
+
+    #[test]
+    fn local_fragment_leaves_out_delivered_candidates() {
+        let delivered = HashSet::from([HOST.to_string()]);
+        let (fragment, candidates) = local_fragment(ANSWER, &delivered, true);
+        assert!(!fragment.contains(HOST));
+        assert!(fragment.contains(SRFLX));
+        assert!(fragment.ends_with("a=end-of-candidates\r\n"));
+        assert_eq!(candidates, [SRFLX]);
+
+        let (fragment, candidates) = local_fragment(ANSWER, &local_candidates(ANSWER), false);
+        assert!(!fragment.contains("a=candidate:"));
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn lists_local_candidates() {
+        assert_eq!(
+            local_candidates(ANSWER),
+            HashSet::from([HOST.to_string(), SRFLX.to_string()])
+        );
+    }
+}
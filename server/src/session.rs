@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use webrtc::{
+    ice_transport::ice_connection_state::RTCIceConnectionState,
+    peer_connection::{RTCPeerConnection, peer_connection_state::RTCPeerConnectionState},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionKind {
+    Publisher,
+    Viewer,
+}
+
+/// A WHIP or WHEP resource created by a POST
+pub struct Session {
+    pub kind: SessionKind,
+    pub stream_key: String,
+    pub created_at: SystemTime,
+    pub pc: Arc<RTCPeerConnection>,
+    /// Bearer token the session was created with, needed to delete it
+    pub token: Option<String>,
+    /// Since when the session has not been connected, a new session is not connected yet
+    unhealthy_since: std::sync::Mutex<Option<Instant>>,
+    /// Local ICE candidates the client was sent, in the answer or by PATCH
+    delivered_candidates: std::sync::Mutex<HashSet<String>>,
+}
+
+impl Session {
+    pub fn new(
+        kind: SessionKind,
+        stream_key: String,
+        pc: Arc<RTCPeerConnection>,
+        token: Option<String>,
+    ) -> Self {
+        Self {
+            kind,
+            stream_key,
+            created_at: SystemTime::now(),
+            pc,
+            token,
+            unhealthy_since: std::sync::Mutex::new(Some(Instant::now())),
+            delivered_candidates: Default::default(),
+        }
+    }
+
+    /// Whether both the peer connection and its ICE transport are up, a
+    /// transport losing consent freshness is reported as disconnected
+    pub fn is_connected(&self) -> bool {
+        self.pc.connection_state() == RTCPeerConnectionState::Connected
+            && matches!(
+                self.pc.ice_connection_state(),
+                RTCIceConnectionState::Connected | RTCIceConnectionState::Completed
+            )
+    }
+
+    pub fn delivered_candidates(&self) -> HashSet<String> {
+        self.delivered_candidates.lock().unwrap().clone()
+    }
+
+    /// Remembers candidates as sent to the client, replacing the previous ones
+    /// on an ICE restart
+    pub fn deliver_candidates(&self, candidates: impl IntoIterator<Item = String>, restart: bool) {
+        let mut delivered = self.delivered_candidates.lock().unwrap();
+        if restart {
+            delivered.clear();
+        }
+        delivered.extend(candidates);
+    }
+
+    /// Refreshes the connection health and returns for how long the session has been unhealthy
+    pub fn unhealthy_for(&self) -> Option<Duration> {
+        self.update_health(self.is_connected())
+    }
+
+    fn update_health(&self, connected: bool) -> Option<Duration> {
+        let mut unhealthy_since = self.unhealthy_since.lock().unwrap();
+        if connected {
+            *unhealthy_since = None;
+        } else if unhealthy_since.is_none() {
+            *unhealthy_since = Some(Instant::now());
+        }
+        unhealthy_since.map(|since| since.elapsed())
+    }
+}
+
+/// Every live session, by resource id
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<Uuid, Arc<Session>>>>,
+}
+
+impl SessionRegistry {
+    pub async fn insert(&self, session_id: Uuid, session: Session) {
+        self.sessions
+            .lock()
+            .await
+            .insert(session_id, Arc::new(session));
+    }
+
+    pub async fn get(&self, session_id: &Uuid) -> Option<Arc<Session>> {
+        self.sessions.lock().await.get(session_id).cloned()
+    }
+
+    pub async fn list(&self) -> Vec<(Uuid, Arc<Session>)> {
+        self.sessions
+            .lock()
+            .await
+            .iter()
+            .map(|(session_id, session)| (*session_id, session.clone()))
+            .collect()
+    }
+
+    pub async fn remove(&self, session_id: &Uuid) -> Option<Arc<Session>> {
+        self.sessions.lock().await.remove(session_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{api::APIBuilder, peer_connection::configuration::RTCConfiguration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn health_is_marked_then_cleared_on_recovery() {
+        let pc = APIBuilder::new()
+            .build()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        let session = Session::new(
+            SessionKind::Viewer,
+            "stream".to_string(),
+            Arc::new(pc),
+            None,
+        );
+
+        // Not connected yet, unhealthy since it was created
+        let created = session.unhealthy_for().unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(session.update_health(false).unwrap() >= created + Duration::from_millis(10));
+
+        assert_eq!(session.update_health(true), None);
+        let lost = session.update_health(false).unwrap();
+        assert!(lost < Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(session.update_health(false).unwrap() >= lost + Duration::from_millis(10));
+        assert_eq!(session.update_health(true), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{io, path::Path, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use uuid::Uuid;
+use webrtc::rtp::{
+    codecs::h264::H264Payloader,
+    packet::Packet,
+    packetizer::{Packetizer, new_packetizer},
+    sequence::new_random_sequencer,
+};
+
+/// How often the slate picture is sent again, for viewers joining meanwhile
+pub const SLATE_INTERVAL: Duration = Duration::from_secs(1);
+
+const H264_CLOCK_RATE: u32 = 90_000;
+
+/// Largest RTP payload, leaving room for the headers within a common MTU
+const MAX_PAYLOAD: usize = 1200;
+
+/// A still picture shown to viewers waiting for a publisher, an H264 Annex B
+/// keyframe (SPS, PPS and IDR slice)
+pub struct Slate {
+    picture: Bytes,
+}
+
+impl Slate {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let picture = std::fs::read(path)?;
+        if !picture.starts_with(&[0, 0, 1]) && !picture.starts_with(&[0, 0, 0, 1]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an H264 Annex B picture", path.display()),
+            ));
+        }
+        Ok(Self {
+            picture: picture.into(),
+        })
+    }
+}
+
+/// The slate as one RTP source, its pictures numbered one after the other
+pub struct SlateSender {
+    slate: Arc<Slate>,
+    pub ssrc: u32,
+    packetizer: Box<dyn Packetizer + Send + Sync>,
+}
+
+impl SlateSender {
+    pub fn new(slate: Arc<Slate>) -> Self {
+        let ssrc = Uuid::new_v4().as_fields().0.max(1);
+        let packetizer = new_packetizer(
+            MAX_PAYLOAD,
+            // Viewers' tracks set their own payload type
+            0,
+            ssrc,
+            Box::<H264Payloader>::default(),
+            Box::new(new_random_sequencer()),
+            H264_CLOCK_RATE,
+        );
+        Self {
+            slate,
+            ssrc,
+            packetizer: Box::new(packetizer),
+        }
+    }
+
+    /// Packets of the next picture
+    pub fn next_picture(&mut self) -> Vec<Packet> {
+        let samples = (SLATE_INTERVAL.as_secs_f64() * f64::from(H264_CLOCK_RATE)) as u32;
+        self.packetizer
+            .packetize(&self.slate.picture, samples)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::api::media_engine::MIME_TYPE_H264;
+
+    use super::*;
+    use crate::codec;
+
+    /// An SPS, a PPS and an IDR slice too large for one packet
+    fn picture() -> Vec<u8> {
+        let mut picture = vec![
+            0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1f, 0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80,
+        ];
+        picture.extend([0, 0, 0, 1, 0x65]);
+        picture.extend(std::iter::repeat_n(0x88, 3000));
+        picture
+    }
+
+    #[test]
+    fn only_annex_b_pictures_load() {
+        let path = std::env::temp_dir().join(format!("omniroom-{}-slate.h264", std::process::id()));
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n").unwrap();
+        let err = Slate::load(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, picture()).unwrap();
+        let slate = Slate::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(slate.unwrap().picture, picture());
+    }
+
+    #[test]
+    fn pictures_are_keyframes_numbered_one_after_the_other() {
+        let mut sender = SlateSender::new(Arc::new(Slate {
+            picture: picture().into(),
+        }));
+        let first = sender.next_picture();
+        assert!(first.len() > 1);
+        assert!(
+            first
+                .iter()
+                .all(|packet| packet.header.ssrc == sender.ssrc
+                    && packet.payload.len() <= MAX_PAYLOAD)
+        );
+        assert!(codec::is_keyframe(MIME_TYPE_H264, &first[0].payload));
+        // The picture ends on its last packet
+        let (last, rest) = first.split_last().unwrap();
+        assert!(last.header.marker);
+        assert!(rest.iter().all(|packet| !packet.header.marker));
+
+        let second = sender.next_picture();
+        assert_eq!(
+            second[0].header.sequence_number,
+            last.header.sequence_number.wrapping_add(1)
+        );
+        assert_eq!(
+            second[0]
+                .header
+                .timestamp
+                .wrapping_sub(last.header.timestamp),
+            H264_CLOCK_RATE
+        );
+    }
+}
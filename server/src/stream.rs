@@ -0,0 +1,1736 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    sync::{Mutex, watch},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+use webrtc::{
+    api::media_engine::{MIME_TYPE_H264, MIME_TYPE_OPUS},
+    peer_connection::{RTCPeerConnection, peer_connection_state::RTCPeerConnectionState},
+    rtcp::{
+        self,
+        payload_feedbacks::{
+            full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
+            receiver_estimated_maximum_bitrate::ReceiverEstimatedMaximumBitrate,
+        },
+        receiver_report::ReceiverReport,
+        transport_feedbacks::{
+            transport_layer_cc::TransportLayerCc, transport_layer_nack::TransportLayerNack,
+        },
+    },
+    rtp::packet::Packet,
+    rtp_transceiver::rtp_codec::{RTCRtpCodecCapability, RTPCodecType},
+    track::track_local::{TrackLocalWriter, track_local_static_rtp::TrackLocalStaticRTP},
+};
+
+use crate::{
+    auth::unix_now,
+    bwe::BandwidthEstimator,
+    codec,
+    fanout::{self, FanoutReceiver, PacketSource},
+    layer::{ForwardingWriter, Layer},
+    retransmit::RtxStreams,
+    slate::{SLATE_INTERVAL, Slate, SlateSender},
+    svc::SvcLayer,
+};
+
+/// Viewers joining together share a single keyframe request
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the viewers' bandwidth estimates are sent to the publisher
+const BANDWIDTH_ESTIMATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Share of a viewer's estimate a simulcast or SVC layer may take
+const LAYER_HEADROOM: f64 = 0.9;
+
+/// Share of the lowest layer a viewer's estimate must cover to keep getting video
+const PAUSE_SHARE: f64 = 0.5;
+
+/// How long video stays paused before trying again
+const PAUSE_RETRY: Duration = Duration::from_secs(10);
+
+/// Why a viewer's layer cannot be selected
+#[derive(Debug, thiserror::Error)]
+pub enum LayerError {
+    #[error("No layered track {0}")]
+    NoTrack(String),
+    #[error("No layer {0}")]
+    NoLayer(String),
+}
+
+/// What happens to a live publisher when another one publishes the same stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PublisherPolicy {
+    /// The newcomer is refused while the publisher is connected
+    #[default]
+    Reject,
+    /// The newcomer replaces the publisher, viewers carrying on with its tracks
+    Takeover,
+}
+
+impl std::str::FromStr for PublisherPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "takeover" => Ok(Self::Takeover),
+            _ => Err(format!(
+                "unknown publisher policy '{s}', expected reject or takeover"
+            )),
+        }
+    }
+}
+
+/// Whether a stream has a publisher, or its viewers are waiting for one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamStatus {
+    Waiting,
+    Live,
+}
+
+/// The stream has a connected publisher and does not let another take over
+#[derive(Debug)]
+pub struct AlreadyPublishing;
+
+/// Sessions a new publisher leaves without media, for the caller to end
+#[derive(Debug, Default)]
+pub struct Displaced {
+    /// The publisher it took over from
+    pub publisher: Option<Uuid>,
+    /// Viewers of tracks now sent with a codec they did not negotiate
+    pub viewers: Vec<Uuid>,
+}
+
+/// A publisher track as received by one viewer
+pub struct ViewerTrack {
+    published: Arc<PublishedTrack>,
+    writer: Arc<ForwardingWriter>,
+    /// SSRC the viewer receives the track with, to match its feedback
+    ssrc: u32,
+    /// Media id of the track in the viewer's session
+    mid: String,
+    /// Layer the viewer asked for, instead of following its estimate
+    pinned: Option<usize>,
+    /// SVC layers the viewer asked for, instead of following its estimate
+    svc_pinned: Option<SvcLayer>,
+    selected: watch::Sender<usize>,
+    /// Since when video is paused as the viewer cannot keep up
+    paused_at: Option<Instant>,
+}
+
+impl ViewerTrack {
+    pub fn new(
+        published: Arc<PublishedTrack>,
+        local: Arc<TrackLocalStaticRTP>,
+        ssrc: u32,
+        mid: String,
+    ) -> Self {
+        // Start low and let the estimate move the viewer up
+        let layer = published.layer_for(0).unwrap_or(0);
+        let codec = published.codec();
+        Self {
+            writer: Arc::new(ForwardingWriter::new(
+                local,
+                &published.layers,
+                layer,
+                codec.mime_type,
+                codec.clock_rate,
+            )),
+            published,
+            ssrc,
+            mid,
+            pinned: None,
+            svc_pinned: None,
+            selected: watch::Sender::new(layer),
+            paused_at: None,
+        }
+    }
+
+    /// Receives `published` instead, which took the place of the track
+    fn rebind(&mut self, published: Arc<PublishedTrack>) {
+        let layer = published.layer_for(0).unwrap_or(0);
+        self.writer = Arc::new(self.writer.rebind(&published.layers, layer));
+        self.selected.send_replace(layer);
+        self.pinned = None;
+        self.svc_pinned = None;
+        self.paused_at = None;
+        self.published = published;
+    }
+
+    fn is_simulcast(&self) -> bool {
+        self.published.layers.len() > 1
+    }
+
+    /// Bitrate of what the viewer gets of the track
+    fn bitrate(&self) -> u64 {
+        if self.writer.is_paused() {
+            return 0;
+        }
+        let layer = &self.published.layers[self.writer.layer()];
+        match self.writer.svc_target() {
+            Some(target) => layer.svc.read().unwrap().bitrate_within(&target),
+            None => layer.bitrate.bitrate(),
+        }
+    }
+
+    /// Bitrate of the lowest layers the track can be sent with, once known
+    fn lowest_bitrate(&self) -> Option<u64> {
+        let layer = &self.published.layers[self.published.layer_for(0)?];
+        let svc = layer.svc.read().unwrap();
+        let bitrate = if svc.is_layered() {
+            svc.bitrate_within(&SvcLayer {
+                spatial: 0,
+                temporal: 0,
+            })
+        } else {
+            layer.bitrate.bitrate()
+        };
+        Some(bitrate).filter(|&bitrate| bitrate > 0)
+    }
+
+    fn is_pinned(&self) -> bool {
+        self.pinned.is_some() || self.svc_pinned.is_some()
+    }
+
+    /// Pauses video the viewer cannot keep up with, or tries again once paused
+    /// long enough, returns whether it resumed
+    fn follow_budget(&mut self, budget: u64) -> Option<bool> {
+        if let Some(paused_at) = self.paused_at {
+            if paused_at.elapsed() < PAUSE_RETRY {
+                return Some(false);
+            }
+            self.paused_at = None;
+            self.writer.resume();
+            return Some(true);
+        }
+        if !self.is_pinned()
+            && self
+                .lowest_bitrate()
+                .is_some_and(|lowest| (budget as f64) < lowest as f64 * PAUSE_SHARE)
+        {
+            self.paused_at = Some(Instant::now());
+            self.writer.pause();
+            // Resume from the lowest layers and let the estimate move up again
+            if let Some(layer) = self.published.layer_for(0) {
+                self.select(layer);
+                if self.published.layers[layer]
+                    .svc
+                    .read()
+                    .unwrap()
+                    .is_layered()
+                {
+                    self.writer.set_svc_target(Some(SvcLayer {
+                        spatial: 0,
+                        temporal: 0,
+                    }));
+                }
+            }
+            return Some(false);
+        }
+        None
+    }
+
+    fn stats(&self) -> TrackStats {
+        let layer = &self.published.layers[self.writer.layer()];
+        let svc_target = self.writer.svc_target();
+        TrackStats {
+            mid: self.mid.clone(),
+            kind: self.published.kind.to_string(),
+            encoding_id: Some(layer.rid.clone()).filter(|rid| !rid.is_empty()),
+            spatial_layer_id: svc_target.map(|target| target.spatial),
+            temporal_layer_id: svc_target.map(|target| target.temporal),
+            paused: self.writer.is_paused(),
+            bitrate: self.bitrate(),
+        }
+    }
+
+    /// Switches to `layer` on its next keyframe, returns whether it changed
+    fn select(&self, layer: usize) -> bool {
+        self.selected.send_if_modified(|selected| {
+            let changed = *selected != layer;
+            *selected = layer;
+            changed
+        })
+    }
+}
+
+/// Tracks of one viewer, forwarded to once it is connected
+struct Viewer {
+    tracks: Vec<ViewerTrack>,
+    tasks: Vec<JoinHandle<()>>,
+    bwe: BandwidthEstimator,
+}
+
+impl Viewer {
+    fn receives(&self, published: &Arc<PublishedTrack>) -> bool {
+        self.tracks
+            .iter()
+            .any(|track| Arc::ptr_eq(&track.published, published))
+    }
+
+    /// Moves the viewer from `previous` to `published`, which took its place
+    fn rebind(&mut self, previous: &Arc<PublishedTrack>, published: &Arc<PublishedTrack>) {
+        for (index, track) in self.tracks.iter_mut().enumerate() {
+            if !Arc::ptr_eq(&track.published, previous) {
+                continue;
+            }
+            track.rebind(published.clone());
+            // Already forwarding, start over from the new track
+            if let Some(task) = self.tasks.get_mut(index) {
+                task.abort();
+                *task = published.forward(track.writer.clone(), track.selected.subscribe());
+            }
+        }
+    }
+
+    /// Bitrate of the layers currently sent to the viewer
+    fn incoming_bitrate(&self) -> u64 {
+        self.tracks
+            .iter()
+            .filter(|track| track.published.kind == RTPCodecType::Video)
+            .map(ViewerTrack::bitrate)
+            .sum()
+    }
+
+    /// Moves the simulcast and SVC tracks not pinned by the viewer to the best
+    /// layers its estimate allows, pausing video below the lowest ones,
+    /// returns whether any needs a keyframe
+    fn follow_estimate(&mut self, estimate: u64) -> bool {
+        let video_tracks = self
+            .tracks
+            .iter()
+            .filter(|track| track.published.kind == RTPCodecType::Video)
+            .count();
+        let budget = estimate / video_tracks.max(1) as u64;
+        let mut switched = false;
+        let mut resumed = false;
+        for track in &mut self.tracks {
+            if track.published.kind != RTPCodecType::Video {
+                continue;
+            }
+            if let Some(resume) = track.follow_budget(budget) {
+                resumed |= resume;
+                continue;
+            }
+            if track.is_simulcast()
+                && track.pinned.is_none()
+                && let Some(layer) = track.published.layer_for(budget)
+            {
+                switched |= track.select(layer);
+            }
+            let layer = *track.selected.borrow();
+            // Released before the writer's lock, taken the other way round when forwarding
+            let (layered, target) = {
+                let svc = track.published.layers[layer].svc.read().unwrap();
+                (
+                    svc.is_layered(),
+                    svc.target_for(budget as f64 * LAYER_HEADROOM),
+                )
+            };
+            if track.svc_pinned.is_none() && layered {
+                let current = track.writer.svc_target();
+                // Upper spatial layers cannot be decoded before a keyframe
+                switched |= track.writer.set_svc_target(target)
+                    && target.map(|target| target.spatial) > current.map(|current| current.spatial);
+            }
+        }
+        // The estimate fell with the video, start over from what is sent again
+        if resumed {
+            self.bwe.reset();
+        }
+        switched || resumed
+    }
+
+    fn stats(&self, state: StreamStatus) -> ViewerStats {
+        ViewerStats {
+            state,
+            estimate: self.bwe.estimate(),
+            bitrate: self.incoming_bitrate(),
+            tracks: self.tracks.iter().map(ViewerTrack::stats).collect(),
+        }
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+/// What a viewer gets and what it can take, in bits per second
+#[derive(serde::Serialize)]
+pub struct ViewerStats {
+    state: StreamStatus,
+    estimate: Option<u64>,
+    bitrate: u64,
+    tracks: Vec<TrackStats>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackStats {
+    mid: String,
+    kind: String,
+    encoding_id: Option<String>,
+    spatial_layer_id: Option<u8>,
+    temporal_layer_id: Option<u8>,
+    paused: bool,
+    bitrate: u64,
+}
+
+/// A stream as listed by the stream directory
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInfo {
+    id: String,
+    state: StreamStatus,
+    /// Unix time the publisher started at
+    started_at: Option<u64>,
+    viewers: usize,
+    /// Free form details the publisher gave, such as a title
+    metadata: HashMap<String, String>,
+    tracks: Vec<TrackInfo>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackInfo {
+    kind: String,
+    codec: String,
+    layers: Vec<LayerInfo>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LayerInfo {
+    encoding_id: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    bitrate: u64,
+}
+
+/// A track as described by the publisher's offer
+pub struct OfferedTrack {
+    pub mid: String,
+    pub kind: RTPCodecType,
+    pub codec: RTCRtpCodecCapability,
+    /// Simulcast layers, a single empty one without simulcast
+    pub rids: Vec<String>,
+}
+
+struct Publisher {
+    session_id: Uuid,
+    pc: Arc<RTCPeerConnection>,
+    /// Unix time it started at
+    started_at: u64,
+    metadata: HashMap<String, String>,
+    /// The stream's tracks by the mid the publisher sends them on
+    tracks: HashMap<String, Arc<PublishedTrack>>,
+}
+
+impl Publisher {
+    /// Whether it is connected or still connecting, rather than gone
+    fn is_live(&self) -> bool {
+        !matches!(
+            self.pc.connection_state(),
+            RTCPeerConnectionState::Disconnected
+                | RTCPeerConnectionState::Failed
+                | RTCPeerConnectionState::Closed
+        )
+    }
+
+    fn video_ssrcs(&self) -> Vec<u32> {
+        self.tracks
+            .values()
+            .filter(|track| track.kind == RTPCodecType::Video)
+            .flat_map(|track| track.layers.iter().filter_map(Layer::ssrc))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct StreamState {
+    publisher: Option<Publisher>,
+    /// Every track published so far, kept across publishers so viewers carry on
+    tracks: Vec<Arc<PublishedTrack>>,
+    viewers: HashMap<Uuid, Viewer>,
+    last_keyframe_request: Option<Instant>,
+    /// Since when the publisher is gone, viewers waiting for it to come back
+    publisher_left: Option<Instant>,
+    /// Whether the slate is being sent to the waiting viewers
+    slate_shown: bool,
+}
+
+/// One audio or video track of a stream, fanned out to the viewers
+pub struct PublishedTrack {
+    pub kind: RTPCodecType,
+    codec: std::sync::Mutex<RTCRtpCodecCapability>,
+    /// Made for viewers waiting for a publisher, the codec parameters being unknown
+    placeholder: AtomicBool,
+    pub layers: Vec<Layer>,
+}
+
+impl PublishedTrack {
+    fn new(
+        kind: RTPCodecType,
+        codec: RTCRtpCodecCapability,
+        rids: Vec<String>,
+        gop_cache: bool,
+    ) -> Self {
+        let gop_cache = gop_cache && kind == RTPCodecType::Video;
+        Self {
+            kind,
+            codec: std::sync::Mutex::new(codec),
+            placeholder: AtomicBool::new(false),
+            layers: rids
+                .into_iter()
+                .map(|rid| Layer::new(rid, gop_cache))
+                .collect(),
+        }
+    }
+
+    /// Codec the publisher sends, or the one it is expected to send
+    pub fn codec(&self) -> RTCRtpCodecCapability {
+        self.codec.lock().unwrap().clone()
+    }
+
+    /// Whether nothing was published on it yet, only its codec being known
+    pub fn is_placeholder(&self) -> bool {
+        self.placeholder.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether it is another codec, which viewers of the previous one
+    /// cannot decode
+    fn set_codec(&self, codec: RTCRtpCodecCapability) -> bool {
+        self.placeholder.store(false, Ordering::Relaxed);
+        let mut current = self.codec.lock().unwrap();
+        let changed = !current.mime_type.eq_ignore_ascii_case(&codec.mime_type);
+        *current = codec;
+        changed
+    }
+
+    pub fn layer(&self, rid: &str) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.rid == rid)
+    }
+
+    fn has_rids(&self, rids: &[String]) -> bool {
+        self.layers.len() == rids.len()
+            && self
+                .layers
+                .iter()
+                .zip(rids)
+                .all(|(layer, rid)| layer.rid == *rid)
+    }
+
+    /// Highest bitrate layer fitting in `budget`, or the lowest one, among
+    /// the layers being sent
+    fn layer_for(&self, budget: u64) -> Option<usize> {
+        let mut layers: Vec<(usize, u64)> = self
+            .layers
+            .iter()
+            .map(|layer| layer.bitrate.bitrate())
+            .enumerate()
+            .filter(|&(_, bitrate)| bitrate > 0)
+            .collect();
+        layers.sort_by_key(|&(_, bitrate)| bitrate);
+        layers
+            .iter()
+            .rev()
+            .find(|&&(_, bitrate)| bitrate as f64 <= budget as f64 * LAYER_HEADROOM)
+            .or(layers.first())
+            .map(|&(layer, _)| layer)
+    }
+
+    /// Spawns a task forwarding the selected layer to a viewer, switching
+    /// layers on keyframes
+    fn forward(
+        self: &Arc<Self>,
+        writer: Arc<ForwardingWriter>,
+        mut selected: watch::Receiver<usize>,
+    ) -> JoinHandle<()> {
+        if self.layers.len() == 1 {
+            return self.layers[0].subscribe(writer);
+        }
+
+        // The viewer may have moved before it was connected
+        selected.mark_changed();
+        let current = writer.layer();
+        let (receiver, backlog) = self.layers[current].receiver();
+        let source = LayerSwitch {
+            track: self.clone(),
+            writer: writer.clone(),
+            selected,
+            current,
+            receiver,
+            pending: None,
+        };
+        fanout::forward(writer, backlog, source)
+    }
+}
+
+/// Packets of the layer a viewer selected, switching over on a keyframe of
+/// the new layer
+struct LayerSwitch {
+    track: Arc<PublishedTrack>,
+    writer: Arc<ForwardingWriter>,
+    selected: watch::Receiver<usize>,
+    current: usize,
+    receiver: FanoutReceiver,
+    /// Layer being switched to
+    pending: Option<(usize, FanoutReceiver)>,
+}
+
+#[async_trait]
+impl PacketSource for LayerSwitch {
+    async fn next_packet(&mut self) -> Option<Arc<Packet>> {
+        loop {
+            tokio::select! {
+                changed = self.selected.changed() => {
+                    changed.ok()?;
+                    let layer = *self.selected.borrow_and_update();
+                    self.pending = (layer != self.current)
+                        .then(|| (layer, self.track.layers[layer].receiver().0));
+                }
+                packet = self.receiver.next_packet() => return packet,
+                Some(packet) = pending_packet(&mut self.pending) => {
+                    // Decoding can only carry on from a keyframe of the new layer
+                    if codec::is_keyframe(&self.track.codec().mime_type, &packet.payload)
+                        && let Some((layer, next)) = self.pending.take()
+                    {
+                        (self.current, self.receiver) = (layer, next);
+                        self.writer.switch(layer);
+                        return Some(packet);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Next packet of the layer being switched to, if any
+async fn pending_packet(pending: &mut Option<(usize, FanoutReceiver)>) -> Option<Arc<Packet>> {
+    let (_, receiver) = pending.as_mut()?;
+    receiver.next_packet().await
+}
+
+/// A published stream and the viewers attached to it
+#[derive(Default)]
+pub struct Stream {
+    gop_cache: bool,
+    slate: Option<Arc<Slate>>,
+    state: std::sync::Mutex<StreamState>,
+}
+
+impl Stream {
+    pub fn new(gop_cache: bool, slate: Option<Arc<Slate>>) -> Self {
+        Self {
+            gop_cache,
+            slate,
+            ..Default::default()
+        }
+    }
+
+    /// Whether a new publisher would be let in by `policy`
+    pub fn accepts_publisher(&self, policy: PublisherPolicy) -> bool {
+        let state = self.state.lock().unwrap();
+        policy == PublisherPolicy::Takeover
+            || !state.publisher.as_ref().is_some_and(Publisher::is_live)
+    }
+
+    /// Makes `session_id` the publisher of the tracks it offered, taking over
+    /// the tracks of the same kind in order, preferably with the same layers.
+    /// Returns the sessions it displaced
+    pub fn set_publisher(
+        self: &Arc<Self>,
+        session_id: Uuid,
+        pc: Arc<RTCPeerConnection>,
+        offered: Vec<OfferedTrack>,
+        metadata: HashMap<String, String>,
+        policy: PublisherPolicy,
+    ) -> Result<Displaced, AlreadyPublishing> {
+        let displaced = {
+            let mut state = self.state.lock().unwrap();
+            if policy == PublisherPolicy::Reject
+                && state.publisher.as_ref().is_some_and(Publisher::is_live)
+            {
+                return Err(AlreadyPublishing);
+            }
+            state.publisher_left = None;
+            let mut unclaimed = state.tracks.clone();
+            let mut tracks = HashMap::new();
+            let mut switched = Vec::new();
+            for offered in offered {
+                let same_layers = unclaimed
+                    .iter()
+                    .position(|track| track.kind == offered.kind && track.has_rids(&offered.rids));
+                let same_kind = || {
+                    unclaimed
+                        .iter()
+                        .position(|track| track.kind == offered.kind)
+                };
+                let track = match same_layers {
+                    Some(index) => {
+                        let track = unclaimed.remove(index);
+                        for layer in &track.layers {
+                            layer.set_ssrc(0);
+                        }
+                        if track.set_codec(offered.codec) {
+                            switched.push(track.clone());
+                        }
+                        track
+                    }
+                    None => {
+                        let track = Arc::new(PublishedTrack::new(
+                            offered.kind,
+                            offered.codec,
+                            offered.rids,
+                            self.gop_cache,
+                        ));
+                        match same_kind() {
+                            // Other layers, such as viewers waiting on a track without
+                            // simulcast, which are moved to the new one
+                            Some(index) => {
+                                let previous = unclaimed.remove(index);
+                                if let Some(slot) = state
+                                    .tracks
+                                    .iter_mut()
+                                    .find(|track| Arc::ptr_eq(track, &previous))
+                                {
+                                    *slot = track.clone();
+                                }
+                                if previous
+                                    .codec()
+                                    .mime_type
+                                    .eq_ignore_ascii_case(&track.codec().mime_type)
+                                {
+                                    for viewer in state.viewers.values_mut() {
+                                        viewer.rebind(&previous, &track);
+                                    }
+                                } else {
+                                    switched.push(previous);
+                                }
+                            }
+                            None => state.tracks.push(track.clone()),
+                        }
+                        track
+                    }
+                };
+                tracks.insert(offered.mid, track);
+            }
+            let viewers = state
+                .viewers
+                .iter()
+                .filter(|(_, viewer)| switched.iter().any(|track| viewer.receives(track)))
+                .map(|(session_id, _)| *session_id)
+                .collect();
+            let publisher = state
+                .publisher
+                .replace(Publisher {
+                    session_id,
+                    pc,
+                    started_at: unix_now(),
+                    metadata,
+                    tracks,
+                })
+                .map(|publisher| publisher.session_id);
+            Displaced { publisher, viewers }
+        };
+
+        let stream = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(BANDWIDTH_ESTIMATE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(stream) = Weak::upgrade(&stream) else {
+                    break;
+                };
+                match stream.send_bandwidth_estimate(&session_id).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => eprintln!("Failed to send bandwidth estimate: {err}"),
+                }
+            }
+        });
+        Ok(displaced)
+    }
+
+    /// Track the publisher `session_id` sends on `mid`, its `rid` layer bound
+    /// to the SSRC, codec and dependency descriptor extension actually received
+    pub fn publisher_track(
+        &self,
+        session_id: &Uuid,
+        mid: &str,
+        rid: &str,
+        ssrc: u32,
+        codec: RTCRtpCodecCapability,
+        dependency_descriptor_id: Option<u8>,
+    ) -> Option<Arc<PublishedTrack>> {
+        let state = self.state.lock().unwrap();
+        let publisher = state
+            .publisher
+            .as_ref()
+            .filter(|publisher| publisher.session_id == *session_id)?;
+        let track = publisher.tracks.get(mid)?.clone();
+        let layer = track.layer(rid)?;
+        layer.set_ssrc(ssrc);
+        layer
+            .svc
+            .write()
+            .unwrap()
+            .reset(&codec.mime_type, dependency_descriptor_id);
+        // The codec it offered, down to its parameters
+        track.set_codec(codec);
+        Some(track)
+    }
+
+    /// Tracks for a viewer to receive, an H264 video and an Opus audio track
+    /// until something is published
+    pub fn tracks(&self) -> Vec<Arc<PublishedTrack>> {
+        let mut state = self.state.lock().unwrap();
+        if state.tracks.is_empty() {
+            state.tracks = [
+                (RTPCodecType::Video, MIME_TYPE_H264, 90000, 0),
+                (RTPCodecType::Audio, MIME_TYPE_OPUS, 48000, 2),
+            ]
+            .into_iter()
+            .map(|(kind, mime_type, clock_rate, channels)| {
+                let codec = RTCRtpCodecCapability {
+                    mime_type: mime_type.to_owned(),
+                    clock_rate,
+                    channels,
+                    ..Default::default()
+                };
+                let track = PublishedTrack::new(kind, codec, vec![String::new()], self.gop_cache);
+                track.placeholder.store(true, Ordering::Relaxed);
+                Arc::new(track)
+            })
+            .collect();
+        }
+        state.tracks.clone()
+    }
+
+    /// Asks the publisher for a keyframe, unless one was just asked for
+    pub async fn request_keyframe(&self) -> webrtc::error::Result<()> {
+        let (pc, media_ssrcs) = {
+            let mut state = self.state.lock().unwrap();
+            if state
+                .last_keyframe_request
+                .is_some_and(|at| at.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+            {
+                return Ok(());
+            }
+            let Some(publisher) = &state.publisher else {
+                return Ok(());
+            };
+            let request = (publisher.pc.clone(), publisher.video_ssrcs());
+            state.last_keyframe_request = Some(Instant::now());
+            request
+        };
+
+        if media_ssrcs.is_empty() {
+            return Ok(());
+        }
+        let plis: Vec<Box<dyn rtcp::packet::Packet + Send + Sync>> = media_ssrcs
+            .into_iter()
+            .map(|media_ssrc| {
+                Box::new(PictureLossIndication {
+                    sender_ssrc: 0,
+                    media_ssrc,
+                }) as _
+            })
+            .collect();
+        pc.write_rtcp(&plis).await.map(|_| ())
+    }
+
+    /// Takes in the RTCP a viewer sends: lost packets are sent again, keyframe
+    /// requests are forwarded to the publisher and the rest feeds the viewer's
+    /// bandwidth estimate
+    pub async fn on_viewer_rtcp(
+        &self,
+        session_id: &Uuid,
+        packets: &[Box<dyn rtcp::packet::Packet + Send + Sync>],
+        rtx_streams: &RtxStreams,
+    ) -> webrtc::error::Result<()> {
+        let mut wants_keyframe = false;
+        let mut lost = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(viewer) = state.viewers.get_mut(session_id) else {
+                return Ok(());
+            };
+            for packet in packets {
+                let packet = packet.as_any();
+                if let Some(nack) = packet.downcast_ref::<TransportLayerNack>() {
+                    let Some(track) = viewer
+                        .tracks
+                        .iter()
+                        .find(|track| track.ssrc == nack.media_ssrc)
+                    else {
+                        continue;
+                    };
+                    // Only what was sent since the last layer switch can be found again
+                    let forwarding = track.writer.forwarding.lock().unwrap();
+                    let layer = &track.published.layers[forwarding.layer];
+                    let retransmit = layer.retransmit.lock().unwrap();
+                    lost.extend(
+                        nack.nacks
+                            .iter()
+                            .flat_map(|pair| pair.packet_list())
+                            .filter_map(|sequence_number| {
+                                let original = forwarding
+                                    .rewriter
+                                    .original_sequence_number(sequence_number)?;
+                                let packet = retransmit.get(original)?;
+                                Some((sequence_number, packet))
+                            })
+                            .map(|(sequence_number, packet)| {
+                                let packet = forwarding.rewriter.resend(sequence_number, &packet);
+                                (nack.media_ssrc, track.writer.local.clone(), packet)
+                            }),
+                    );
+                } else if packet.is::<PictureLossIndication>() || packet.is::<FullIntraRequest>() {
+                    wants_keyframe = true;
+                } else if let Some(remb) = packet.downcast_ref::<ReceiverEstimatedMaximumBitrate>()
+                {
+                    viewer.bwe.on_remb(remb.bitrate);
+                } else if let Some(rr) = packet.downcast_ref::<ReceiverReport>() {
+                    for report in &rr.reports {
+                        viewer.bwe.on_loss(f64::from(report.fraction_lost) / 256.0);
+                    }
+                } else if let Some(twcc) = packet.downcast_ref::<TransportLayerCc>() {
+                    viewer.bwe.on_twcc(twcc);
+                }
+            }
+        }
+
+        for (media_ssrc, local, packet) in lost {
+            // Without a negotiated RTX stream the packet goes again on the media stream
+            match rtx_streams.get(media_ssrc) {
+                Some(rtx) => rtx.retransmit(&packet).await.map(|_| ())?,
+                None => local.write_rtp(&packet).await.map(|_| ())?,
+            }
+        }
+
+        if wants_keyframe {
+            self.request_keyframe().await?;
+        }
+        Ok(())
+    }
+
+    /// Moves viewers between simulcast and SVC layers as their estimates allow and
+    /// sends the publisher what its viewers can take, returns false once
+    /// `session_id` is no longer the publisher
+    async fn send_bandwidth_estimate(&self, session_id: &Uuid) -> webrtc::error::Result<bool> {
+        let (pc, ssrcs, bitrate, switched) = {
+            let mut state = self.state.lock().unwrap();
+            let publisher = match &state.publisher {
+                Some(publisher) if publisher.session_id == *session_id => publisher,
+                _ => return Ok(false),
+            };
+            let (pc, ssrcs) = (publisher.pc.clone(), publisher.video_ssrcs());
+
+            // A viewer that fell behind cannot decode until the next keyframe
+            let mut lagged = false;
+            for track in &state.tracks {
+                for layer in &track.layers {
+                    layer.bitrate.sample();
+                    layer.svc.read().unwrap().sample();
+                    lagged |= layer.take_lagged();
+                }
+            }
+            let mut switched = false;
+            let mut estimates = Vec::new();
+            for viewer in state.viewers.values_mut() {
+                let incoming = viewer.incoming_bitrate();
+                if let Some(estimate) = viewer.bwe.update(incoming) {
+                    switched |= viewer.follow_estimate(estimate);
+                    estimates.push(estimate);
+                }
+            }
+            // With simulcast the weakest viewer takes a lower layer instead of
+            // holding everyone back
+            let simulcast = state.tracks.iter().any(|track| track.layers.len() > 1);
+            let bitrate = if simulcast {
+                estimates.into_iter().max()
+            } else {
+                estimates.into_iter().min()
+            };
+            (pc, ssrcs, bitrate, switched || lagged)
+        };
+
+        if switched {
+            self.request_keyframe().await?;
+        }
+        if let Some(bitrate) = bitrate
+            && !ssrcs.is_empty()
+        {
+            pc.write_rtcp(&[Box::new(ReceiverEstimatedMaximumBitrate {
+                sender_ssrc: 0,
+                bitrate: bitrate as f32,
+                ssrcs,
+            })])
+            .await?;
+        }
+        Ok(true)
+    }
+
+    /// Pins a viewer's layered tracks, all of them or the one on `mid`, to
+    /// the `rid` simulcast layer and up to the `svc` spatial and temporal
+    /// layers, or lets them follow its estimate again without either
+    pub async fn select_layer(
+        &self,
+        session_id: &Uuid,
+        mid: Option<&str>,
+        rid: Option<&str>,
+        svc: Option<SvcLayer>,
+    ) -> Result<(), LayerError> {
+        let switched = {
+            let mut state = self.state.lock().unwrap();
+            let Some(viewer) = state.viewers.get_mut(session_id) else {
+                return Ok(());
+            };
+            let mut tracks: Vec<&mut ViewerTrack> = viewer
+                .tracks
+                .iter_mut()
+                .filter(|track| track.published.kind == RTPCodecType::Video)
+                .filter(|track| mid.is_none_or(|mid| track.mid == mid))
+                .collect();
+            if tracks.is_empty() {
+                return Err(LayerError::NoTrack(mid.unwrap_or_default().to_string()));
+            }
+
+            let mut switched = false;
+            if let Some(rid) = rid {
+                let mut found = false;
+                for track in tracks.iter_mut().filter(|track| track.is_simulcast()) {
+                    if let Some(layer) = track
+                        .published
+                        .layers
+                        .iter()
+                        .position(|layer| layer.rid == rid)
+                    {
+                        track.pinned = Some(layer);
+                        switched |= track.select(layer);
+                        found = true;
+                    }
+                }
+                if !found {
+                    return Err(LayerError::NoLayer(rid.to_string()));
+                }
+            }
+            if let Some(target) = svc {
+                for track in &mut tracks {
+                    let current = track.writer.svc_target();
+                    track.svc_pinned = Some(target);
+                    switched |= track.writer.set_svc_target(Some(target))
+                        && current.is_some_and(|current| target.spatial > current.spatial);
+                }
+            }
+            if rid.is_none() && svc.is_none() {
+                for track in &mut tracks {
+                    track.pinned = None;
+                    track.svc_pinned = None;
+                }
+            }
+            switched
+        };
+
+        if switched {
+            // Nothing to do if the keyframe request is refused, the next one brings the switch
+            let _ = self.request_keyframe().await;
+        }
+        Ok(())
+    }
+
+    pub fn viewer_stats(&self, session_id: &Uuid) -> Option<ViewerStats> {
+        let state = self.state.lock().unwrap();
+        let status = match state.publisher {
+            Some(_) => StreamStatus::Live,
+            None => StreamStatus::Waiting,
+        };
+        state
+            .viewers
+            .get(session_id)
+            .map(|viewer| viewer.stats(status))
+    }
+
+    pub fn add_viewer(&self, session_id: Uuid, tracks: Vec<ViewerTrack>) {
+        let viewer = Viewer {
+            tracks,
+            tasks: Vec::new(),
+            bwe: BandwidthEstimator::default(),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .viewers
+            .insert(session_id, viewer);
+    }
+
+    /// Starts forwarding to a viewer whose connection is up, with a keyframe
+    /// to start decoding from
+    pub async fn start_viewer(&self, session_id: &Uuid) -> webrtc::error::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(viewer) = state.viewers.get_mut(session_id) else {
+                return Ok(());
+            };
+            // Already forwarding, the connection came back from disconnected
+            if !viewer.tasks.is_empty() {
+                return Ok(());
+            }
+
+            viewer.tasks = viewer
+                .tracks
+                .iter()
+                .map(|track| {
+                    track
+                        .published
+                        .forward(track.writer.clone(), track.selected.subscribe())
+                })
+                .collect();
+        }
+        self.request_keyframe().await
+    }
+
+    /// Detaches a publisher or viewer session from the stream
+    pub fn remove_session(&self, session_id: &Uuid) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .publisher
+            .as_ref()
+            .is_some_and(|publisher| publisher.session_id == *session_id)
+        {
+            state.publisher = None;
+            state.publisher_left = Some(Instant::now());
+        }
+        state.viewers.remove(session_id);
+    }
+
+    fn info(&self, id: &str) -> StreamInfo {
+        let state = self.state.lock().unwrap();
+        let publisher = state.publisher.as_ref();
+        // The publisher's tracks, in the order viewers get them
+        let tracks = state.tracks.iter().filter(|track| {
+            publisher.is_some_and(|publisher| {
+                publisher
+                    .tracks
+                    .values()
+                    .any(|published| Arc::ptr_eq(published, track))
+            })
+        });
+        StreamInfo {
+            id: id.to_string(),
+            state: match publisher {
+                Some(_) => StreamStatus::Live,
+                None => StreamStatus::Waiting,
+            },
+            started_at: publisher.map(|publisher| publisher.started_at),
+            viewers: state.viewers.len(),
+            metadata: publisher
+                .map(|publisher| publisher.metadata.clone())
+                .unwrap_or_default(),
+            tracks: tracks
+                .map(|track| TrackInfo {
+                    kind: track.kind.to_string(),
+                    codec: track.codec().mime_type,
+                    layers: track
+                        .layers
+                        .iter()
+                        .map(|layer| {
+                            let resolution = layer.resolution();
+                            LayerInfo {
+                                encoding_id: Some(layer.rid.clone()).filter(|rid| !rid.is_empty()),
+                                width: resolution.map(|(width, _)| width),
+                                height: resolution.map(|(_, height)| height),
+                                bitrate: layer.bitrate.bitrate(),
+                            }
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Sends the slate to the viewers until a publisher comes, if there is one
+    pub fn show_slate(self: &Arc<Self>) {
+        let Some(slate) = self.slate.clone() else {
+            return;
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.slate_shown || state.publisher.is_some() || state.viewers.is_empty() {
+                return;
+            }
+            state.slate_shown = true;
+        }
+
+        let stream = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut sender = SlateSender::new(slate);
+            let mut interval = tokio::time::interval(SLATE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(stream) = Weak::upgrade(&stream) else {
+                    break;
+                };
+                let Some(tracks) = stream.slate_tracks(sender.ssrc) else {
+                    break;
+                };
+                for packet in sender.next_picture() {
+                    let keyframe = codec::is_keyframe(MIME_TYPE_H264, &packet.payload);
+                    for layer in tracks.iter().flat_map(|track| &track.layers) {
+                        layer.publish(packet.clone(), keyframe);
+                    }
+                }
+            }
+        });
+    }
+
+    /// H264 video tracks for the slate to go to as `ssrc`, none once a
+    /// publisher came or the viewers left
+    fn slate_tracks(&self, ssrc: u32) -> Option<Vec<Arc<PublishedTrack>>> {
+        let mut state = self.state.lock().unwrap();
+        if state.publisher.is_some() || state.viewers.is_empty() {
+            state.slate_shown = false;
+            return None;
+        }
+        let tracks: Vec<Arc<PublishedTrack>> = state
+            .tracks
+            .iter()
+            .filter(|track| {
+                track.kind == RTPCodecType::Video
+                    && track.codec().mime_type.eq_ignore_ascii_case(MIME_TYPE_H264)
+            })
+            .cloned()
+            .collect();
+        // A publisher coming in resets the SSRCs, and the packets on their way are dropped
+        for layer in tracks.iter().flat_map(|track| &track.layers) {
+            layer.set_ssrc(ssrc);
+        }
+        Some(tracks)
+    }
+
+    /// Viewers of a publisher gone for longer than `grace`
+    fn abandoned_viewers(&self, grace: Duration) -> Vec<Uuid> {
+        let state = self.state.lock().unwrap();
+        if state
+            .publisher_left
+            .is_none_or(|left| left.elapsed() <= grace)
+        {
+            return Vec::new();
+        }
+        state.viewers.keys().copied().collect()
+    }
+
+    fn is_idle(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.publisher.is_none() && state.viewers.is_empty()
+    }
+}
+
+/// Every stream with a publisher or a viewer, by stream key
+#[derive(Clone, Default)]
+pub struct StreamRegistry {
+    streams: Arc<Mutex<HashMap<String, Arc<Stream>>>>,
+    gop_cache: bool,
+    slate: Option<Arc<Slate>>,
+}
+
+impl StreamRegistry {
+    pub fn new(gop_cache: bool, slate: Option<Slate>) -> Self {
+        Self {
+            gop_cache,
+            slate: slate.map(Arc::new),
+            ..Default::default()
+        }
+    }
+
+    pub async fn get(&self, stream_key: &str) -> Option<Arc<Stream>> {
+        self.streams.lock().await.get(stream_key).cloned()
+    }
+
+    pub async fn get_or_create(&self, stream_key: &str) -> Arc<Stream> {
+        self.streams
+            .lock()
+            .await
+            .entry(stream_key.to_string())
+            .or_insert_with(|| Arc::new(Stream::new(self.gop_cache, self.slate.clone())))
+            .clone()
+    }
+
+    /// Every stream with a publisher that is `listed`, for the stream directory
+    pub async fn live(&self, listed: impl Fn(&str) -> bool) -> Vec<StreamInfo> {
+        let streams = self.streams.lock().await;
+        let mut live: Vec<StreamInfo> = streams
+            .iter()
+            .filter(|(id, _)| listed(id))
+            .map(|(id, stream)| stream.info(id))
+            .filter(|info| info.state == StreamStatus::Live)
+            .collect();
+        live.sort_by(|a, b| a.id.cmp(&b.id));
+        live
+    }
+
+    pub async fn info(&self, stream_key: &str) -> Option<StreamInfo> {
+        let streams = self.streams.lock().await;
+        streams
+            .get(stream_key)
+            .map(|stream| stream.info(stream_key))
+    }
+
+    /// Viewers of every stream whose publisher did not come back within `grace`
+    pub async fn abandoned_viewers(&self, grace: Duration) -> Vec<Uuid> {
+        let streams = self.streams.lock().await;
+        streams
+            .values()
+            .flat_map(|stream| stream.abandoned_viewers(grace))
+            .collect()
+    }
+
+    /// Detaches a session from its stream and forgets the stream once nobody uses it
+    pub async fn remove_session(&self, stream_key: &str, session_id: &Uuid) {
+        let mut streams = self.streams.lock().await;
+        if let Some(stream) = streams.get(stream_key) {
+            stream.remove_session(session_id);
+            if stream.is_idle() {
+                streams.remove(stream_key);
+            } else {
+                stream.show_slate();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use webrtc::{
+        api::{APIBuilder, media_engine::MIME_TYPE_VP8},
+        peer_connection::configuration::RTCConfiguration,
+        rtp::header::Header,
+    };
+
+    use super::*;
+
+    async fn peer_connection() -> Arc<RTCPeerConnection> {
+        Arc::new(
+            APIBuilder::new()
+                .build()
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        )
+    }
+
+    fn video(mime_type: &str, rids: &[&str]) -> Vec<OfferedTrack> {
+        vec![OfferedTrack {
+            mid: "0".to_string(),
+            kind: RTPCodecType::Video,
+            codec: codec(mime_type),
+            rids: rids.iter().map(|rid| rid.to_string()).collect(),
+        }]
+    }
+
+    fn codec(mime_type: &str) -> RTCRtpCodecCapability {
+        RTCRtpCodecCapability {
+            mime_type: mime_type.to_string(),
+            clock_rate: 90000,
+            ..Default::default()
+        }
+    }
+
+    async fn publish(
+        stream: &Arc<Stream>,
+        session_id: Uuid,
+        offered: Vec<OfferedTrack>,
+        policy: PublisherPolicy,
+    ) -> Result<(Arc<RTCPeerConnection>, Displaced), AlreadyPublishing> {
+        let pc = peer_connection().await;
+        let displaced =
+            stream.set_publisher(session_id, pc.clone(), offered, HashMap::new(), policy)?;
+        Ok((pc, displaced))
+    }
+
+    fn add_viewer(stream: &Stream, session_id: Uuid) {
+        let track = stream.tracks().remove(0);
+        let local = Arc::new(TrackLocalStaticRTP::new(
+            track.codec(),
+            "video".to_string(),
+            "stream".to_string(),
+        ));
+        stream.add_viewer(
+            session_id,
+            vec![ViewerTrack::new(track, local, 1, "0".to_string())],
+        );
+    }
+
+    /// Sequence number and timestamp the viewer gets a packet of `ssrc` with
+    fn sent(
+        stream: &Stream,
+        viewer: &Uuid,
+        ssrc: u32,
+        sequence_number: u16,
+        timestamp: u32,
+    ) -> (u16, u32) {
+        let packet = Packet {
+            header: Header {
+                ssrc,
+                sequence_number,
+                timestamp,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let state = stream.state.lock().unwrap();
+        let track = &state.viewers[viewer].tracks[0];
+        let mut forwarding = track.writer.forwarding.lock().unwrap();
+        let packet = forwarding.rewriter.rewrite(&packet);
+        (packet.header.sequence_number, packet.header.timestamp)
+    }
+
+    fn assert_follows(previous: (u16, u32), next: (u16, u32)) {
+        assert_eq!(next.0, previous.0.wrapping_add(1));
+        assert!(
+            next.1.wrapping_sub(previous.1) > 0 && next.1.wrapping_sub(previous.1) < 0x8000_0000
+        );
+    }
+
+    #[tokio::test]
+    async fn simulcast_viewers_switch_layers_on_keyframes() {
+        let track = Arc::new(PublishedTrack::new(
+            RTPCodecType::Video,
+            codec(MIME_TYPE_H264),
+            vec!["h".to_string(), "l".to_string()],
+            false,
+        ));
+        track.layers[0].set_ssrc(1);
+        track.layers[1].set_ssrc(2);
+        let local = Arc::new(TrackLocalStaticRTP::new(
+            codec(MIME_TYPE_H264),
+            "video".to_string(),
+            "stream".to_string(),
+        ));
+        let writer = Arc::new(ForwardingWriter::new(
+            local,
+            &track.layers,
+            1,
+            MIME_TYPE_H264.to_string(),
+            90000,
+        ));
+        let selected = watch::Sender::new(1);
+        let mut source = LayerSwitch {
+            track: track.clone(),
+            writer: writer.clone(),
+            selected: selected.subscribe(),
+            current: 1,
+            receiver: track.layers[1].receiver().0,
+            pending: None,
+        };
+        let publish = |layer: usize, sequence_number: u16, keyframe: bool| {
+            let packet = Packet {
+                header: Header {
+                    ssrc: layer as u32 + 1,
+                    sequence_number,
+                    ..Default::default()
+                },
+                // An IDR slice or a non IDR one
+                payload: vec![if keyframe { 0x65 } else { 0x41 }].into(),
+            };
+            track.layers[layer].publish(packet, keyframe);
+        };
+
+        publish(1, 1, false);
+        assert_eq!(source.next_packet().await.unwrap().header.ssrc, 2);
+
+        selected.send_replace(0);
+        let nothing = tokio::time::timeout(Duration::from_millis(10), source.next_packet()).await;
+        assert!(nothing.is_err());
+        publish(0, 10, false);
+        publish(1, 2, false);
+        publish(0, 11, true);
+        publish(1, 3, false);
+        // The previous layer carries on until the keyframe, and stops there
+        let mut previous = Vec::new();
+        loop {
+            let packet = source.next_packet().await.unwrap();
+            if packet.header.ssrc == 1 {
+                assert_eq!(packet.header.sequence_number, 11);
+                break;
+            }
+            previous.push(packet.header.sequence_number);
+        }
+        assert!([2, 3].starts_with(&previous));
+        assert_eq!(writer.layer(), 0);
+        publish(0, 12, false);
+        assert_eq!(
+            source.next_packet().await.unwrap().header.sequence_number,
+            12
+        );
+    }
+
+    #[tokio::test]
+    async fn second_publisher_is_rejected_while_the_first_is_live() {
+        let stream = Arc::new(Stream::default());
+        let first = Uuid::new_v4();
+        publish(
+            &stream,
+            first,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+
+        assert!(!stream.accepts_publisher(PublisherPolicy::Reject));
+        assert!(stream.accepts_publisher(PublisherPolicy::Takeover));
+        let second = publish(
+            &stream,
+            Uuid::new_v4(),
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await;
+        assert!(second.is_err());
+        assert_eq!(
+            stream
+                .state
+                .lock()
+                .unwrap()
+                .publisher
+                .as_ref()
+                .unwrap()
+                .session_id,
+            first
+        );
+    }
+
+    #[tokio::test]
+    async fn publisher_reconnects_once_the_previous_one_is_gone() {
+        let stream = Arc::new(Stream::default());
+        let first = Uuid::new_v4();
+        let (pc, _) = publish(
+            &stream,
+            first,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+        let track = stream
+            .publisher_track(&first, "0", "", 1, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        pc.close().await.unwrap();
+
+        assert!(stream.accepts_publisher(PublisherPolicy::Reject));
+        let second = Uuid::new_v4();
+        let (_, displaced) = publish(
+            &stream,
+            second,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+        assert_eq!(displaced.publisher, Some(first));
+        assert!(displaced.viewers.is_empty());
+        // Gone with the first publisher
+        assert!(
+            stream
+                .publisher_track(&first, "0", "", 1, codec(MIME_TYPE_H264), None)
+                .is_none()
+        );
+        let reconnected = stream
+            .publisher_track(&second, "0", "", 2, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        assert!(Arc::ptr_eq(&track, &reconnected));
+    }
+
+    #[tokio::test]
+    async fn takeover_continues_the_viewers_numbering() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+        let first = Uuid::new_v4();
+        publish(
+            &stream,
+            first,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+        stream
+            .publisher_track(&first, "0", "", 1, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        sent(&stream, &viewer, 1, 100, 1000);
+        let last = sent(&stream, &viewer, 1, 101, 4000);
+
+        let second = Uuid::new_v4();
+        let (_, displaced) = publish(
+            &stream,
+            second,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+        assert_eq!(displaced.publisher, Some(first));
+        assert!(displaced.viewers.is_empty());
+        stream
+            .publisher_track(&second, "0", "", 2, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        let next = sent(&stream, &viewer, 2, 50000, 3_000_000_000);
+        assert_follows(last, next);
+        assert_follows(next, sent(&stream, &viewer, 2, 50001, 3_000_003_000));
+    }
+
+    #[tokio::test]
+    async fn takeover_with_other_layers_moves_the_viewers() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+        let first = Uuid::new_v4();
+        publish(
+            &stream,
+            first,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+        stream
+            .publisher_track(&first, "0", "", 1, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        let last = sent(&stream, &viewer, 1, 65535, 1000);
+
+        let second = Uuid::new_v4();
+        let (_, displaced) = publish(
+            &stream,
+            second,
+            video(MIME_TYPE_H264, &["h", "l"]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+        assert!(displaced.viewers.is_empty());
+        let track = stream
+            .publisher_track(&second, "0", "l", 2, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        assert!(stream.state.lock().unwrap().viewers[&viewer].receives(&track));
+        // In place of the track it took over, next to the audio one
+        assert_eq!(stream.tracks().len(), 2);
+        assert_follows(last, sent(&stream, &viewer, 2, 7, 90));
+    }
+
+    #[tokio::test]
+    async fn takeover_with_another_codec_displaces_the_viewers() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+        publish(
+            &stream,
+            Uuid::new_v4(),
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+
+        let (_, displaced) = publish(
+            &stream,
+            Uuid::new_v4(),
+            video(MIME_TYPE_VP8, &[""]),
+            PublisherPolicy::Takeover,
+        )
+        .await
+        .unwrap();
+        assert_eq!(displaced.viewers, vec![viewer]);
+    }
+
+    #[tokio::test]
+    async fn waiting_viewers_get_the_publishers_track() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+        let waited = stream.tracks().remove(0);
+        assert!(waited.is_placeholder());
+        // The slate goes to them meanwhile
+        let slate = stream.slate_tracks(7).unwrap();
+        assert!(Arc::ptr_eq(&slate[0], &waited));
+        assert_eq!(waited.layers[0].ssrc(), Some(7));
+
+        let publisher = Uuid::new_v4();
+        let (_, displaced) = publish(
+            &stream,
+            publisher,
+            video(MIME_TYPE_H264, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+        assert!(displaced.viewers.is_empty());
+        assert!(stream.slate_tracks(7).is_none());
+        let track = stream
+            .publisher_track(&publisher, "0", "", 1, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        assert!(Arc::ptr_eq(&track, &waited));
+        assert!(!track.is_placeholder());
+        assert!(stream.state.lock().unwrap().viewers[&viewer].receives(&track));
+        let first = sent(&stream, &viewer, 1, 30000, 500);
+        assert_follows(first, sent(&stream, &viewer, 1, 30001, 3500));
+    }
+
+    #[tokio::test]
+    async fn waiting_viewers_move_to_a_simulcast_track() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+
+        let publisher = Uuid::new_v4();
+        let (_, displaced) = publish(
+            &stream,
+            publisher,
+            video(MIME_TYPE_H264, &["h", "l"]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+        assert!(displaced.viewers.is_empty());
+        let track = stream
+            .publisher_track(&publisher, "0", "h", 1, codec(MIME_TYPE_H264), None)
+            .unwrap();
+        assert!(stream.state.lock().unwrap().viewers[&viewer].receives(&track));
+        assert!(Arc::ptr_eq(&stream.tracks()[0], &track));
+    }
+
+    #[tokio::test]
+    async fn waiting_viewers_are_displaced_by_another_codec() {
+        let stream = Arc::new(Stream::default());
+        let viewer = Uuid::new_v4();
+        add_viewer(&stream, viewer);
+
+        let (_, displaced) = publish(
+            &stream,
+            Uuid::new_v4(),
+            video(MIME_TYPE_VP8, &[""]),
+            PublisherPolicy::Reject,
+        )
+        .await
+        .unwrap();
+        assert_eq!(displaced.viewers, vec![viewer]);
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+
+use webrtc::{
+    api::media_engine::{MIME_TYPE_AV1, MIME_TYPE_VP9},
+    rtp::packet::Packet,
+};
+
+use crate::{bits::BitReader, bwe::BitrateMeter};
+
+/// Header extension carrying the AV1 dependency descriptor
+pub const DEPENDENCY_DESCRIPTOR_URI: &str =
+    "https://aomediacodec.github.io/av1-rtp-spec/#dependency-descriptor-rtp-header-extension";
+
+/// Spatial and temporal layer of a scalable (SVC) video stream
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SvcLayer {
+    pub spatial: u8,
+    pub temporal: u8,
+}
+
+impl SvcLayer {
+    /// Whether a receiver decoding up to `target` needs this layer
+    pub fn is_within(&self, target: &SvcLayer) -> bool {
+        self.spatial <= target.spatial && self.temporal <= target.temporal
+    }
+}
+
+/// Where a packet sits in a scalable stream
+#[derive(Clone, Copy, Debug)]
+pub struct SvcPacket {
+    pub layer: SvcLayer,
+    pub start_of_frame: bool,
+    pub end_of_frame: bool,
+}
+
+#[derive(Debug)]
+enum SvcCodec {
+    Vp9,
+    Av1 { dependency_descriptor_id: u8 },
+}
+
+/// Reads the layers of the packets of a published track, and how much each
+/// layer weighs
+#[derive(Debug, Default)]
+pub struct SvcParser {
+    codec: Option<SvcCodec>,
+    /// AV1 frame templates as `(template_id_offset, layer of each template)`,
+    /// sent along keyframes only
+    templates: Option<(u8, Vec<SvcLayer>)>,
+    bitrates: HashMap<SvcLayer, BitrateMeter>,
+}
+
+impl SvcParser {
+    /// Starts over for a track sent with this codec and dependency descriptor
+    /// extension id
+    pub fn reset(&mut self, mime_type: &str, dependency_descriptor_id: Option<u8>) {
+        self.codec = if mime_type.eq_ignore_ascii_case(MIME_TYPE_VP9) {
+            Some(SvcCodec::Vp9)
+        } else if mime_type.eq_ignore_ascii_case(MIME_TYPE_AV1) {
+            dependency_descriptor_id.map(|dependency_descriptor_id| SvcCodec::Av1 {
+                dependency_descriptor_id,
+            })
+        } else {
+            None
+        };
+        self.templates = None;
+        self.bitrates.clear();
+    }
+
+    /// Takes in a published packet: learns AV1 templates and weighs its layer
+    pub fn observe(&mut self, packet: &Packet) {
+        if let Some(SvcCodec::Av1 {
+            dependency_descriptor_id,
+        }) = self.codec
+            && let Some(descriptor) = packet.header.get_extension(dependency_descriptor_id)
+            && let Some(templates) = parse_av1_templates(&descriptor)
+        {
+            self.templates = Some(templates);
+        }
+        if let Some(svc) = self.parse(packet) {
+            self.bitrates
+                .entry(svc.layer)
+                .or_default()
+                .add(packet.payload.len());
+        }
+    }
+
+    pub fn parse(&self, packet: &Packet) -> Option<SvcPacket> {
+        match self.codec.as_ref()? {
+            SvcCodec::Vp9 => parse_vp9(&packet.payload),
+            SvcCodec::Av1 {
+                dependency_descriptor_id,
+            } => {
+                let descriptor = packet.header.get_extension(*dependency_descriptor_id)?;
+                let (offset, layers) = self.templates.as_ref()?;
+                parse_av1(&descriptor, *offset, layers)
+            }
+        }
+    }
+
+    pub fn sample(&self) {
+        for bitrate in self.bitrates.values() {
+            bitrate.sample();
+        }
+    }
+
+    /// Whether the stream has more than one layer
+    pub fn is_layered(&self) -> bool {
+        self.bitrates.len() > 1
+    }
+
+    /// Bitrate of everything a receiver decoding up to `target` gets
+    pub fn bitrate_within(&self, target: &SvcLayer) -> u64 {
+        self.bitrates
+            .iter()
+            .filter(|(layer, _)| layer.is_within(target))
+            .map(|(_, bitrate)| bitrate.bitrate())
+            .sum()
+    }
+
+    /// Highest layers fitting in `budget`, or the lowest ones
+    pub fn target_for(&self, budget: f64) -> Option<SvcLayer> {
+        let mut targets: Vec<(SvcLayer, u64)> = self
+            .bitrates
+            .keys()
+            .map(|layer| (*layer, self.bitrate_within(layer)))
+            .collect();
+        targets.sort_by_key(|&(layer, bitrate)| (bitrate, layer));
+        targets
+            .iter()
+            .rev()
+            .find(|&&(_, bitrate)| bitrate as f64 <= budget)
+            .or(targets.first())
+            .map(|&(layer, _)| layer)
+    }
+}
+
+/// VP9 payload descriptor (RFC 9628), only present with the L bit
+fn parse_vp9(payload: &[u8]) -> Option<SvcPacket> {
+    let descriptor = *payload.first()?;
+    let picture_id = descriptor & 0x80 != 0;
+    let layer_indices = descriptor & 0x20 != 0;
+    if !layer_indices {
+        return None;
+    }
+
+    let mut offset = 1;
+    if picture_id {
+        let long = payload.get(offset)? & 0x80 != 0;
+        offset += if long { 2 } else { 1 };
+    }
+    let indices = *payload.get(offset)?;
+    Some(SvcPacket {
+        layer: SvcLayer {
+            spatial: (indices >> 1) & 0x07,
+            temporal: indices >> 5,
+        },
+        start_of_frame: descriptor & 0x08 != 0,
+        end_of_frame: descriptor & 0x04 != 0,
+    })
+}
+
+/// Mandatory fields of the AV1 dependency descriptor
+fn parse_av1(descriptor: &[u8], offset: u8, layers: &[SvcLayer]) -> Option<SvcPacket> {
+    let mut reader = BitReader::new(descriptor);
+    let start_of_frame = reader.read(1)? == 1;
+    let end_of_frame = reader.read(1)? == 1;
+    let template_id = reader.read(6)? as u8;
+    let index = (template_id + 64 - offset) % 64;
+    Some(SvcPacket {
+        layer: *layers.get(index as usize)?,
+        start_of_frame,
+        end_of_frame,
+    })
+}
+
+/// Layers of the templates of an AV1 dependency descriptor carrying its
+/// template dependency structure
+fn parse_av1_templates(descriptor: &[u8]) -> Option<(u8, Vec<SvcLayer>)> {
+    // Mandatory fields only
+    if descriptor.len() <= 3 {
+        return None;
+    }
+    let mut reader = BitReader::new(descriptor);
+    reader.read(24)?;
+    let structure_present = reader.read(1)? == 1;
+    if !structure_present {
+        return None;
+    }
+    // The other extended descriptor flags
+    reader.read(4)?;
+
+    let template_id_offset = reader.read(6)? as u8;
+    let _decode_target_count = reader.read(5)? + 1;
+    let mut layers = Vec::new();
+    let mut layer = SvcLayer {
+        spatial: 0,
+        temporal: 0,
+    };
+    loop {
+        layers.push(layer);
+        match reader.read(2)? {
+            1 => layer.temporal += 1,
+            2 => {
+                layer.temporal = 0;
+                layer.spatial += 1;
+            }
+            3 => break,
+            _ => {}
+        }
+        if layers.len() > 64 {
+            return None;
+        }
+    }
+    Some((template_id_offset, layers))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use webrtc::rtp::header::Header;
+
+    use super::*;
+
+    const DEPENDENCY_DESCRIPTOR_ID: u8 = 3;
+
+    fn layer(spatial: u8, temporal: u8) -> SvcLayer {
+        SvcLayer { spatial, temporal }
+    }
+
+    /// Packs big endian bit fields, padding the last byte with zeros
+    fn bits(fields: &[(u32, usize)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut position = 0;
+        for &(value, width) in fields {
+            for bit in (0..width).rev() {
+                if position % 8 == 0 {
+                    bytes.push(0);
+                }
+                *bytes.last_mut().unwrap() |= (((value >> bit) & 1) as u8) << (7 - position % 8);
+                position += 1;
+            }
+        }
+        bytes
+    }
+
+    /// Dependency descriptor of a packet using template `template_id`
+    fn descriptor(start: bool, end: bool, template_id: u32) -> Vec<u8> {
+        bits(&[
+            (start as u32, 1),
+            (end as u32, 1),
+            (template_id, 6),
+            (1, 16),
+        ])
+    }
+
+    /// Dependency descriptor carrying an L2T2 template structure
+    fn l2t2_descriptor(template_id_offset: u32) -> Vec<u8> {
+        bits(&[
+            (1, 1),
+            (1, 1),
+            (template_id_offset, 6),
+            (1, 16),
+            // template_dependency_structure_present_flag, then the other flags
+            (1, 1),
+            (0, 4),
+            (template_id_offset, 6),
+            // decode_target_count - 1
+            (3, 5),
+            // next_layer_idc of each template: T0 -> T1 -> S1T0 -> S1T1 -> end
+            (1, 2),
+            (2, 2),
+            (1, 2),
+            (3, 2),
+        ])
+    }
+
+    fn av1_packet(descriptor: Vec<u8>) -> Packet {
+        let mut header = Header::default();
+        header
+            .set_extension(DEPENDENCY_DESCRIPTOR_ID, Bytes::from(descriptor))
+            .unwrap();
+        Packet {
+            header,
+            ..Default::default()
+        }
+    }
+
+    fn av1_parser() -> SvcParser {
+        let mut parser = SvcParser::default();
+        parser.reset(MIME_TYPE_AV1, Some(DEPENDENCY_DESCRIPTOR_ID));
+        parser
+    }
+
+    #[test]
+    fn vp9_without_layer_indices() {
+        assert!(parse_vp9(&[0x88, 0x12, 0xff]).is_none());
+    }
+
+    #[test]
+    fn vp9_layer_indices() {
+        // Long picture id, S1 T2, whole frame
+        let svc = parse_vp9(&[0xac, 0x92, 0x34, 0x42, 0xff]).unwrap();
+        assert_eq!(svc.layer, layer(1, 2));
+        assert!(svc.start_of_frame && svc.end_of_frame);
+
+        // Short picture id, S0 T1, frame start
+        let svc = parse_vp9(&[0xa8, 0x12, 0x20]).unwrap();
+        assert_eq!(svc.layer, layer(0, 1));
+        assert!(svc.start_of_frame && !svc.end_of_frame);
+
+        // No picture id
+        let svc = parse_vp9(&[0x24, 0x02]).unwrap();
+        assert_eq!(svc.layer, layer(1, 0));
+        assert!(!svc.start_of_frame && svc.end_of_frame);
+    }
+
+    #[test]
+    fn vp9_truncated() {
+        assert!(parse_vp9(&[]).is_none());
+        assert!(parse_vp9(&[0xa8]).is_none());
+        assert!(parse_vp9(&[0xac, 0x92]).is_none());
+        assert!(parse_vp9(&[0xac, 0x92, 0x34]).is_none());
+    }
+
+    #[test]
+    fn vp9_through_the_parser() {
+        let mut parser = SvcParser::default();
+        parser.reset(MIME_TYPE_VP9, None);
+        let packet = Packet {
+            payload: Bytes::from_static(&[0xa8, 0x12, 0x20]),
+            ..Default::default()
+        };
+        assert_eq!(parser.parse(&packet).unwrap().layer, layer(0, 1));
+
+        // Not a scalable codec
+        parser.reset("video/VP8", None);
+        assert!(parser.parse(&packet).is_none());
+    }
+
+    #[test]
+    fn av1_templates() {
+        let (offset, layers) = parse_av1_templates(&l2t2_descriptor(5)).unwrap();
+        assert_eq!(offset, 5);
+        assert_eq!(layers, [layer(0, 0), layer(0, 1), layer(1, 0), layer(1, 1)]);
+    }
+
+    #[test]
+    fn av1_without_templates() {
+        // Mandatory fields only
+        assert!(parse_av1_templates(&descriptor(true, true, 0)).is_none());
+        // Extended, without a template structure
+        assert!(parse_av1_templates(&bits(&[(0, 24), (0, 5), (0, 11)])).is_none());
+    }
+
+    #[test]
+    fn av1_truncated() {
+        let descriptor = l2t2_descriptor(0);
+        for len in 0..descriptor.len() - 1 {
+            assert!(parse_av1_templates(&descriptor[..len]).is_none());
+        }
+        assert!(parse_av1(&[], 0, &[layer(0, 0)]).is_none());
+    }
+
+    #[test]
+    fn av1_too_many_templates() {
+        let mut fields = vec![(0, 24), (1, 1), (0, 4), (0, 6), (0, 5)];
+        fields.extend([(0, 2); 70]);
+        fields.push((3, 2));
+        assert!(parse_av1_templates(&bits(&fields)).is_none());
+    }
+
+    #[test]
+    fn av1_packets_use_the_last_templates() {
+        let mut parser = av1_parser();
+        let packet = av1_packet(descriptor(true, false, 7));
+        // Templates are not known yet
+        assert!(parser.parse(&packet).is_none());
+
+        parser.observe(&av1_packet(l2t2_descriptor(5)));
+        let svc = parser.parse(&packet).unwrap();
+        assert_eq!(svc.layer, layer(1, 0));
+        assert!(svc.start_of_frame && !svc.end_of_frame);
+
+        // Template ids wrap around at 64
+        parser.observe(&av1_packet(l2t2_descriptor(62)));
+        let svc = parser
+            .parse(&av1_packet(descriptor(true, true, 63)))
+            .unwrap();
+        assert_eq!(svc.layer, layer(0, 1));
+        assert!(
+            parser
+                .parse(&av1_packet(descriptor(true, true, 2)))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn av1_without_extension() {
+        let parser = av1_parser();
+        assert!(parser.parse(&Packet::default()).is_none());
+
+        let mut parser = SvcParser::default();
+        parser.reset(MIME_TYPE_AV1, None);
+        parser.observe(&av1_packet(l2t2_descriptor(0)));
+        assert!(
+            parser
+                .parse(&av1_packet(descriptor(true, true, 0)))
+                .is_none()
+        );
+    }
+
+    fn l2t2_parser() -> SvcParser {
+        let mut parser = SvcParser::default();
+        for (layer, bitrate) in [
+            (layer(0, 0), 100_000),
+            (layer(0, 1), 50_000),
+            (layer(1, 0), 200_000),
+            (layer(1, 1), 100_000),
+        ] {
+            parser
+                .bitrates
+                .insert(layer, BitrateMeter::sampled(bitrate));
+        }
+        parser
+    }
+
+    #[test]
+    fn target_for_budget() {
+        let parser = l2t2_parser();
+        assert!(parser.is_layered());
+        assert_eq!(parser.bitrate_within(&layer(0, 1)), 150_000);
+        assert_eq!(parser.target_for(1_000_000.0), Some(layer(1, 1)));
+        assert_eq!(parser.target_for(450_000.0), Some(layer(1, 1)));
+        assert_eq!(parser.target_for(320_000.0), Some(layer(1, 0)));
+        assert_eq!(parser.target_for(160_000.0), Some(layer(0, 1)));
+        assert_eq!(parser.target_for(120_000.0), Some(layer(0, 0)));
+        // Below the base layers
+        assert_eq!(parser.target_for(10_000.0), Some(layer(0, 0)));
+    }
+
+    #[test]
+    fn target_for_single_layer() {
+        let mut parser = SvcParser::default();
+        assert_eq!(parser.target_for(1_000_000.0), None);
+
+        parser
+            .bitrates
+            .insert(layer(0, 0), BitrateMeter::sampled(100_000));
+        assert!(!parser.is_layered());
+        assert_eq!(parser.target_for(0.0), Some(layer(0, 0)));
+    }
+}
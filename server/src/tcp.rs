@@ -0,0 +1,313 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::network;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        TcpListener, TcpStream, UdpSocket,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+};
+
+/// How long a connection may take to send its first STUN binding request
+const FIRST_PACKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// STUN magic cookie, found in every STUN message header (RFC 5389)
+const STUN_MAGIC_COOKIE: [u8; 4] = [0x21, 0x12, 0xa4, 0x42];
+
+/// Type preference of the TCP candidates, below the UDP host ones (126) so
+/// that UDP wins whenever it gets through
+const TCP_TYPE_PREFERENCE: u32 = 90;
+
+/// Passive ICE-TCP (RFC 6544) in front of the UDP mux.
+///
+/// The ICE agent only speaks UDP, so every TCP connection is relayed through
+/// its own loopback UDP socket to the UDP mux, where it shows up as a peer
+/// reflexive candidate of the session named by the STUN username. Packets
+/// are framed by their 16 bits length on the TCP side (RFC 4571).
+pub struct IceTcpListener {
+    listener: TcpListener,
+    udp_mux: SocketAddr,
+}
+
+impl IceTcpListener {
+    pub fn bind(address: SocketAddr, udp_mux: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::from_std(network::tcp_listener(address)?)?;
+        // The mux listens on all addresses, reach it on the loopback
+        let udp_mux = match udp_mux {
+            SocketAddr::V4(mux) if mux.ip().is_unspecified() => {
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), mux.port())
+            }
+            SocketAddr::V6(mux) if mux.ip().is_unspecified() => {
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), mux.port())
+            }
+            mux => mux,
+        };
+        Ok(Self { listener, udp_mux })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(self) {
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    eprintln!("ICE-TCP accept failed: {err}");
+                    continue;
+                }
+            };
+            let udp_mux = self.udp_mux;
+            tokio::spawn(async move {
+                if let Err(err) = relay(stream, udp_mux).await {
+                    eprintln!("ICE-TCP connection from {peer} ended: {err}");
+                }
+            });
+        }
+    }
+}
+
+async fn relay(stream: TcpStream, udp_mux: SocketAddr) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let (mut reader, writer) = stream.into_split();
+
+    // Nothing is allocated for connections which are not ICE
+    let first = tokio::time::timeout(FIRST_PACKET_TIMEOUT, read_frame(&mut reader))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no STUN request"))??;
+    if first.len() < 20 || first[0] & 0xc0 != 0 || first[4..8] != STUN_MAGIC_COOKIE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a STUN request",
+        ));
+    }
+
+    let local = match udp_mux {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(udp_mux).await?;
+    socket.send(&first).await?;
+
+    tokio::select! {
+        result = tcp_to_udp(reader, &socket) => result,
+        result = udp_to_tcp(&socket, writer) => result,
+    }
+}
+
+async fn read_frame(reader: &mut OwnedReadHalf) -> io::Result<Vec<u8>> {
+    let length = reader.read_u16().await?;
+    let mut frame = vec![0; usize::from(length)];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn tcp_to_udp(mut reader: OwnedReadHalf, socket: &UdpSocket) -> io::Result<()> {
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok(frame) => frame,
+            // The client closed the connection
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        socket.send(&frame).await?;
+    }
+}
+
+async fn udp_to_tcp(socket: &UdpSocket, mut writer: OwnedWriteHalf) -> io::Result<()> {
+    let mut buf = vec![0; usize::from(u16::MAX)];
+    loop {
+        let len = socket.recv(&mut buf[2..]).await?;
+        // Within u16 as the buffer is
+        buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        writer.write_all(&buf[..len + 2]).await?;
+    }
+}
+
+/// Passive TCP candidates advertised for the ICE-TCP listeners
+#[derive(Clone, Default)]
+pub struct IceTcpCandidates {
+    listeners: Vec<SocketAddr>,
+}
+
+impl IceTcpCandidates {
+    pub fn new(listeners: Vec<SocketAddr>) -> Self {
+        Self { listeners }
+    }
+
+    /// Port of the listener reachable on `ip`
+    fn port(&self, ip: IpAddr) -> Option<u16> {
+        self.listeners
+            .iter()
+            .find(|listener| {
+                listener.is_ipv4() == ip.is_ipv4()
+                    && (listener.ip().is_unspecified() || listener.ip() == ip)
+            })
+            .map(SocketAddr::port)
+    }
+
+    /// A description where each UDP host candidate is followed by a passive
+    /// TCP one on the same address, when a listener is reachable there
+    pub fn add_to(&self, sdp: &str) -> String {
+        if self.listeners.is_empty() {
+            return sdp.to_string();
+        }
+        let mut described = String::with_capacity(sdp.len());
+        let mut addresses = HashSet::new();
+        for line in sdp.lines() {
+            if line.starts_with("m=") {
+                addresses.clear();
+            }
+            described.push_str(line);
+            described.push_str("\r\n");
+            if let Some((address, candidate)) = self.tcp_candidate(line)
+                && addresses.insert(address)
+            {
+                described.push_str(&candidate);
+                described.push_str("\r\n");
+            }
+        }
+        described
+    }
+
+    /// Address of a UDP host candidate line, and the matching passive TCP candidate
+    fn tcp_candidate<'a>(&self, line: &'a str) -> Option<(&'a str, String)> {
+        let fields: Vec<&str> = line.strip_prefix("a=candidate:")?.split(' ').collect();
+        let [
+            foundation,
+            component,
+            transport,
+            priority,
+            address,
+            _,
+            "typ",
+            "host",
+            ..,
+        ] = fields.as_slice()
+        else {
+            return None;
+        };
+        if !transport.eq_ignore_ascii_case("udp") {
+            return None;
+        }
+        let port = self.port(address.parse().ok()?)?;
+        let local_preference = priority.parse::<u32>().ok()? >> 8 & 0xffff;
+        let component = component.parse::<u32>().ok()?;
+        let priority = (TCP_TYPE_PREFERENCE << 24) + (local_preference << 8) + (256 - component);
+        Some((
+            address,
+            format!(
+                "a=candidate:tcp{foundation} {component} tcp {priority} {address} {port} typ host tcptype passive"
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "a=candidate:1 1 udp 2130706431 192.0.2.1 50000 typ host";
+
+    fn candidates() -> IceTcpCandidates {
+        IceTcpCandidates::new(vec!["0.0.0.0:4443".parse().unwrap()])
+    }
+
+    /// A STUN binding request without attributes
+    fn binding_request() -> Vec<u8> {
+        let mut request = vec![0x00, 0x01, 0x00, 0x00];
+        request.extend(STUN_MAGIC_COOKIE);
+        request.extend([7; 12]);
+        request
+    }
+
+    fn framed(packet: &[u8]) -> Vec<u8> {
+        let mut frame = (packet.len() as u16).to_be_bytes().to_vec();
+        frame.extend(packet);
+        frame
+    }
+
+    #[test]
+    fn udp_host_candidates_get_a_passive_tcp_one() {
+        let (address, candidate) = candidates().tcp_candidate(HOST).unwrap();
+        assert_eq!(address, "192.0.2.1");
+        assert_eq!(
+            candidate,
+            "a=candidate:tcp1 1 tcp 1526726655 192.0.2.1 4443 typ host tcptype passive"
+        );
+    }
+
+    #[test]
+    fn only_udp_host_candidates_reachable_by_a_listener_get_one() {
+        let candidates = candidates();
+        for line in [
+            "a=candidate:2 1 udp 1694498815 198.51.100.1 50000 typ srflx raddr 0.0.0.0 rport 0",
+            "a=candidate:3 1 tcp 1518280447 192.0.2.1 9 typ host tcptype active",
+            "a=candidate:4 1 udp 2130706431 2001:db8::1 50000 typ host",
+            "a=mid:0",
+        ] {
+            assert!(candidates.tcp_candidate(line).is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn one_tcp_candidate_per_address_and_media() {
+        let sdp = format!(
+            "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n{HOST}\r\n{HOST}\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n{HOST}\r\n"
+        );
+        let described = candidates().add_to(&sdp);
+        assert_eq!(described.matches("tcptype passive").count(), 2);
+        assert_eq!(IceTcpCandidates::default().add_to(&sdp), sdp);
+    }
+
+    #[tokio::test]
+    async fn frames_are_relayed_as_datagrams_both_ways() {
+        let mux = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener =
+            IceTcpListener::bind("127.0.0.1:0".parse().unwrap(), mux.local_addr().unwrap())
+                .unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        // Two frames in one write, split again by their length
+        let mut frames = framed(&binding_request());
+        frames.extend(framed(b"media"));
+        client.write_all(&frames).await.unwrap();
+
+        let mut buf = [0; 1500];
+        let (len, relay) = mux.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], binding_request());
+        assert!(relay.ip().is_loopback());
+        let (len, _) = mux.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"media");
+
+        mux.send_to(b"answer", relay).await.unwrap();
+        let mut frame = [0; 8];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame.as_slice(), framed(b"answer"));
+    }
+
+    #[tokio::test]
+    async fn connections_not_starting_with_stun_are_closed() {
+        let mux = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener =
+            IceTcpListener::bind("127.0.0.1:0".parse().unwrap(), mux.local_addr().unwrap())
+                .unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(listener.run());
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&framed(&[0xff; 20])).await.unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+}
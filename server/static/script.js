@@ -8,7 +8,11 @@ window.addEventListener("load", function(){
     connect();
 });
 function connect() {
-  createCall("default")
+  const params = new URLSearchParams(window.location.search);
+  createCall(params.get("stream") || "default", params.get("token"), {
+    audio: parseInt(params.get("audio") || "1"),
+    video: parseInt(params.get("video") || "1"),
+  });
 }
 
 //===========================STREAM=================================
@@ -22,13 +26,18 @@ function initCalls() {
     }
 }
 
-function createCall(identifier) {
+function createCall(identifier, token, tracks = { audio: 1, video: 1 }) {
     console.log("Calling: " + identifier);
     const configuration = {'iceServers': [{'urls': 'stun:stun.l.google.com:19302'}]}
     connections[identifier] = new RTCPeerConnection(configuration);
     
-    connections[identifier].addTransceiver('audio', { direction: 'recvonly' })
-    connections[identifier].addTransceiver('video', { direction: 'recvonly' })
+    // One transceiver per published track to receive
+    for (var i = 0; i < tracks.audio; i++) {
+        connections[identifier].addTransceiver('audio', { direction: 'recvonly' })
+    }
+    for (var i = 0; i < tracks.video; i++) {
+        connections[identifier].addTransceiver('video', { direction: 'recvonly' })
+    }
     connections[identifier].ontrack = (event) => {
         if (getVideoElement(identifier).srcObject !== event.streams[0]) {
             console.log("Incoming stream");
@@ -42,7 +51,7 @@ function createCall(identifier) {
     connections[identifier].onicecandidate = (event) => {
         if (event.candidate == null) {
             console.log("ICE Candidate was null, done");
-            send_sdp_offer(identifier);
+            send_sdp_offer(identifier, token);
             connections[identifier].onicecandidate = null;
         }
     };
@@ -56,17 +65,24 @@ async function start_gathering(identifier) {
     });
 }
 
-async function send_sdp_offer(identifier) {
-    return fetch("/api/whep", {
-        headers: {
-             Accept: "application/sdp",
-            "Content-Type": "application/sdp",
-            Authorization: "Bearer " + identifier,
-            "User-Agent": "omniroom"
-        },
+async function send_sdp_offer(identifier, token) {
+    const headers = {
+         Accept: "application/sdp",
+        "Content-Type": "application/sdp",
+        "User-Agent": "omniroom"
+    };
+    if (token) {
+        headers.Authorization = "Bearer " + token;
+    }
+    return fetch("/api/whep/" + encodeURIComponent(identifier), {
+        headers: headers,
         method: "POST",
         body: connections[identifier].localDescription.sdp,
     }).then((res) => {
+        const location = res.headers.get("Location");
+        if (location) {
+            watch_status(location, token);
+        }
         return res.text();
     }).then(async (answer) => {
         return connections[identifier].setRemoteDescription({
@@ -76,6 +92,26 @@ async function send_sdp_offer(identifier) {
     });
 }
 
+// Shows whether the stream is waiting for its publisher
+function watch_status(location, token) {
+    const headers = {};
+    if (token) {
+        headers.Authorization = "Bearer " + token;
+    }
+    const poll = () => {
+        fetch(location + "/stats", { headers: headers }).then((res) => {
+            if (!res.ok) {
+                return;
+            }
+            return res.json().then((stats) => {
+                document.getElementById("status").hidden = stats.state !== "waiting";
+                setTimeout(poll, 2000);
+            });
+        });
+    };
+    poll();
+}
+
 function getVideoElement() {
     return document.querySelector("video");
 }